@@ -0,0 +1,140 @@
+// Compares the plain one-instruction-at-a-time interpreter against exec::run() and the block
+// cache, with and without idioms, run with `cargo bench`. The first program repeatedly adds a value
+// through Z, the second one runs a long stretch of code without any branches.
+use std::time::Instant;
+
+use noontide_emu::{block::BlockCache, exec, mem};
+
+const ITERATIONS: i64 = 20_000_000;
+const STRAIGHT_LEN: u64 = 1000;
+const STRAIGHT_ITERATIONS: i64 = 100_000;
+
+const Z: u64 = 0x10000;
+const X: u64 = 0x10008;
+const Y: u64 = 0x10010;
+const CNT: u64 = 0x10018;
+const ONE: u64 = 0x10020;
+const SCRATCH: u64 = 0x11000;
+
+struct Program {
+    mem: Vec<u8>,
+    halt: u64,
+    check: fn(&[u8]),
+}
+
+fn write_code(mem: &mut [u8], start: u64, code: &[(u64, u64, u64)]) {
+    for (i, (a, b, c)) in code.iter().enumerate() {
+        let eip = start as usize + i * 24;
+        mem::write(mem, eip, &a.to_be_bytes());
+        mem::write(mem, eip + 8, &b.to_be_bytes());
+        mem::write(mem, eip + 16, &c.to_be_bytes());
+    }
+}
+
+fn add_loop() -> Program {
+    const HALT: u64 = 5 * 24;
+    let mut mem = vec![0u8; 0x20000];
+    write_code(
+        &mut mem,
+        0,
+        &[
+            (Z, X, 24),       // Z -= X
+            (Y, Z, 48),       // Y -= Z
+            (Z, Z, 72),       // Z = 0
+            (CNT, ONE, HALT), // if --CNT <= 0 goto HALT
+            (Z, Z, 0),        // goto 0
+            (Z, Z, HALT),     // HALT: loop forever
+        ],
+    );
+    mem::write(&mut mem, X as usize, &3i64.to_be_bytes());
+    mem::write(&mut mem, CNT as usize, &ITERATIONS.to_be_bytes());
+    mem::write(&mut mem, ONE as usize, &1i64.to_be_bytes());
+
+    Program {
+        mem,
+        halt: HALT,
+        check: |mem| assert_eq!(mem::read(mem, Y as usize), 3 * ITERATIONS),
+    }
+}
+
+fn straight_line() -> Program {
+    const HALT: u64 = (STRAIGHT_LEN + 2) * 24;
+    let mut mem = vec![0u8; 0x20000];
+
+    // Subtractions between pseudo-random scratch words, all falling through
+    let mut seed: u64 = 0x9E3779B97F4A7C15;
+    let mut code = Vec::new();
+    for eip in (0..STRAIGHT_LEN * 24).step_by(24) {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        let a = SCRATCH + 8 * (seed % 512);
+        let b = SCRATCH + 8 * ((seed >> 20) % 512);
+        code.push((a, b, eip + 24));
+    }
+    code.push((CNT, ONE, HALT));
+    code.push((Z, Z, 0));
+    code.push((Z, Z, HALT));
+    write_code(&mut mem, 0, &code);
+    mem::write(&mut mem, CNT as usize, &STRAIGHT_ITERATIONS.to_be_bytes());
+    mem::write(&mut mem, ONE as usize, &1i64.to_be_bytes());
+
+    Program {
+        mem,
+        halt: HALT,
+        check: |mem| assert_eq!(mem::read(mem, CNT as usize), 0),
+    }
+}
+
+fn bench(name: &str, program: fn() -> Program, f: impl Fn(&mut [u8], u64) -> u64) {
+    let mut program = program();
+    let start = Instant::now();
+    let executed = f(&mut program.mem, program.halt);
+    let elapsed = start.elapsed().as_secs_f64();
+
+    (program.check)(&program.mem);
+    println!(
+        "{name: <10} {executed} instructions in {elapsed:.3}s ({:.1} MIPS)",
+        executed as f64 / elapsed / 1e6
+    );
+}
+
+fn main() {
+    for (title, program) in [
+        ("add loop", add_loop as fn() -> Program),
+        ("straight line", straight_line),
+    ] {
+        println!("{title}:");
+
+        bench("step", program, |mem, halt| {
+            let mut eip = 0;
+            let mut executed = 0;
+            while eip != halt {
+                eip = exec::step(mem, eip).next_eip();
+                executed += 1;
+            }
+            executed
+        });
+
+        bench("run", program, |mem, halt| {
+            let mut eip = 0;
+            let mut executed = 0;
+            while eip != halt {
+                executed += exec::run(mem, &mut eip, 65536).0;
+            }
+            executed
+        });
+
+        for (name, idioms) in [("blocks", false), ("idioms", true)] {
+            bench(name, program, |mem, halt| {
+                let mut cache = BlockCache::new(mem, idioms);
+                let mut eip = 0;
+                let mut executed = 0;
+                while eip != halt {
+                    executed += cache.run(mem, &mut eip, 65536).0;
+                }
+                executed
+            });
+        }
+    }
+}
@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+
+use crate::pdb::DebugData;
+
+// Assembles hex0, hex1 and hex2 sources, laid out the same way parse_hex_file() sees them:
+//   - # and ; start a comment that runs to the end of the line
+//   - Pairs of hex digits are bytes, everything else is ignored
+//   - :name defines a label at the current offset (hex1 and hex2 only)
+//   - &name is the address of a label, ?name its offset from the end of the reference, both
+//     as 8-byte big-endian words (hex1 and hex2 only)
+// Returns the image along with the offset of every line.
+pub fn assemble(inp: &str, labels: bool) -> Result<(Vec<u8>, DebugData), String> {
+    let mut out = Vec::new();
+    let mut debug_data = DebugData {
+        offsets: Vec::new(),
+    };
+    let mut definitions: HashMap<&str, u64> = HashMap::new();
+    // Where each reference goes, filled in once all labels are known
+    let mut references: Vec<(usize, char, &str, usize)> = Vec::new();
+
+    for (i, line) in inp.lines().enumerate() {
+        debug_data.offsets.push((out.len() as u64, line.to_owned()));
+        let error = |reason: String| format!("Line {}: {reason}", i + 1);
+        let code = line.split(['#', ';']).next().unwrap();
+
+        let mut high_nibble = None;
+        let mut rest = code;
+        while let Some(c) = rest.chars().next() {
+            if c == ':' || c == '&' || c == '?' {
+                if !labels {
+                    return Err(error(format!("{c} is not allowed in hex0")));
+                }
+
+                let len = rest.find(char::is_whitespace).unwrap_or(rest.len());
+                let name = &rest[1..len];
+                rest = &rest[len..];
+                if name.is_empty() {
+                    return Err(error(format!("Missing label name after {c}")));
+                }
+                if high_nibble.is_some() {
+                    return Err(error("Odd number of hex digits".to_owned()));
+                }
+
+                if c == ':' {
+                    if definitions.insert(name, out.len() as u64).is_some() {
+                        return Err(error(format!("Duplicate label {name}")));
+                    }
+                } else {
+                    references.push((out.len(), c, name, i));
+                    out.extend_from_slice(&[0; 8]);
+                }
+                continue;
+            }
+
+            rest = &rest[c.len_utf8()..];
+            let Some(nibble) = c.to_digit(16) else {
+                continue;
+            };
+            match high_nibble.take() {
+                Some(high) => out.push((high << 4 | nibble) as u8),
+                None => high_nibble = Some(nibble),
+            }
+        }
+
+        if high_nibble.is_some() {
+            return Err(error("Odd number of hex digits".to_owned()));
+        }
+    }
+
+    for (at, kind, name, i) in references {
+        let Some(&addr) = definitions.get(name) else {
+            return Err(format!("Line {}: Unknown label {name}", i + 1));
+        };
+        let value = match kind {
+            '&' => addr,
+            _ => addr.wrapping_sub(at as u64 + 8),
+        };
+        out[at..at + 8].copy_from_slice(&value.to_be_bytes());
+    }
+
+    Ok((out, debug_data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pdb;
+
+    fn bin(inp: &str) -> Vec<u8> {
+        assemble(inp, true).unwrap().0
+    }
+
+    #[test]
+    fn comments() {
+        assert_eq!(bin("01 # 02\n03;04\n# 05 &x\n"), [1, 3]);
+        assert_eq!(bin("0 1\n0\t2 zz 03"), [1, 2, 3]);
+    }
+
+    #[test]
+    fn references() {
+        let out = bin("00 &end\n:mid ?mid ?end\n:end");
+        let word = |i: usize| u64::from_be_bytes(out[i..i + 8].try_into().unwrap());
+        assert_eq!(out.len(), 25);
+        assert_eq!(word(1), 25);
+        // ? is relative to the end of the reference
+        assert_eq!(word(9), (9u64).wrapping_sub(17));
+        assert_eq!(word(17), 0);
+    }
+
+    #[test]
+    fn errors() {
+        let error = |inp: &str, labels: bool| assemble(inp, labels).err().unwrap();
+        assert_eq!(error("00\n:a\n:a", true), "Line 3: Duplicate label a");
+        assert_eq!(error("00\n&b", true), "Line 2: Unknown label b");
+        assert_eq!(error("012", true), "Line 1: Odd number of hex digits");
+        assert_eq!(error("0:a 1", true), "Line 1: Odd number of hex digits");
+        assert_eq!(error("00 & 00", true), "Line 1: Missing label name after &");
+        assert_eq!(error("00 :a", false), "Line 1: : is not allowed in hex0");
+        assert_eq!(error("0 # 1", true), "Line 1: Odd number of hex digits");
+    }
+
+    #[test]
+    fn debug_files_agree() {
+        let inp = "# Header\n:start 0000000000000001 &data ?start\n\
+                   00:inner\t&start\t; comment :not_a_label\n\
+                   :a-b\t?a-b 02 # &ignored\n\
+                   :data\tFF\n:end";
+        let (out, debug_data) = assemble(inp, true).unwrap();
+        let parsed = pdb::parse_hex_file(inp);
+        assert_eq!(parsed.offsets, debug_data.offsets);
+
+        let labels = pdb::find_labels(&parsed);
+        let expected = [
+            ("start", 0),
+            ("inner", 25),
+            ("a-b", 33),
+            ("data", 42),
+            ("end", 43),
+        ];
+        assert_eq!(labels.len(), expected.len());
+        for (name, addr) in expected {
+            assert_eq!(labels[name], addr, "{name}");
+        }
+        assert_eq!(out.len(), 43);
+        assert_eq!(out[8..16], 42u64.to_be_bytes());
+        assert_eq!(out[25..33], 0u64.to_be_bytes());
+    }
+}
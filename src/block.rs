@@ -0,0 +1,607 @@
+use crate::{
+    exec::{self, Event, IO_START},
+    mem,
+};
+
+const PAGE_SHIFT: u32 = 12;
+const SLOTS_PER_PAGE: usize = 1 << (PAGE_SHIFT - 3);
+// Longer blocks are split, which keeps the cost of decoding an entry in the middle of a
+// block bounded
+const MAX_BLOCK_LEN: usize = 64;
+
+// A run of consecutive instructions decoded once. Any of them may branch out of the block,
+// only an unconditional jump (x x c) ends it, so that small loops fit in a single block.
+struct Block {
+    start: u64,
+    end: u64,
+    // (a, b, c) of every instruction
+    ins: Box<[(u64, u64, u64)]>,
+    // With idioms, the instructions with the idioms among them fused, otherwise empty
+    ops: Box<[Op]>,
+    // Index into ops of every instruction
+    op_index: Box<[u8]>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Op {
+    // A single instruction, a -= b and go to c if the result is <= 0
+    Subleq(u64, u64, u64),
+    // z a; b z; z z c, which adds a to b when z is 0
+    Add { z: u64, a: u64, b: u64, c: u64 },
+    // b b; z a; b z; z z c, which copies a into b when z is 0
+    Move { z: u64, a: u64, b: u64, c: u64 },
+}
+
+impl Op {
+    // Number of instructions
+    fn len(&self) -> u64 {
+        match self {
+            Op::Subleq(..) => 1,
+            Op::Add { .. } => 3,
+            Op::Move { .. } => 4,
+        }
+    }
+}
+
+// The common SUBLEQ macro pattern starting with the first of ins, which is at eip. A clear
+// (x x c) or a jump (z z c) on its own is already a single instruction, and blocks already
+// continue past conditional jumps, so only the sequences ending in one are fused.
+fn find_idiom(ins: &[(u64, u64, u64)], eip: u64) -> Option<Op> {
+    // The fused versions read every operand before writing any, which only gives the same
+    // result when the operands they write are separate words outside of the idiom itself
+    let disjoint = |x: u64, y: u64| x.abs_diff(y) >= 8;
+    let outside = |addr: u64, len: u64| addr + 8 <= eip || addr >= eip + 24 * len;
+
+    if let [i0, i1, i2, i3, ..] = *ins {
+        let (b, z, a) = (i0.0, i1.0, i1.1);
+        if i0 == (b, b, eip + 24)
+            && i1.2 == eip + 48
+            && i2 == (b, z, eip + 72)
+            && (i3.0, i3.1) == (z, z)
+            && disjoint(z, a)
+            && disjoint(z, b)
+            && disjoint(a, b)
+            && outside(b, 4)
+            && outside(z, 4)
+        {
+            return Some(Op::Move { z, a, b, c: i3.2 });
+        }
+    }
+
+    if let [i0, i1, i2, ..] = *ins {
+        let (z, a, b) = (i0.0, i0.1, i1.0);
+        if i0.2 == eip + 24
+            && i1 == (b, z, eip + 48)
+            && (i2.0, i2.1) == (z, z)
+            && disjoint(z, a)
+            && disjoint(z, b)
+            && disjoint(a, b)
+            && outside(b, 3)
+            && outside(z, 3)
+        {
+            return Some(Op::Add { z, a, b, c: i2.2 });
+        }
+    }
+
+    None
+}
+
+// ins of a block starting at start, with the idioms among them fused
+fn fuse(ins: &[(u64, u64, u64)], start: u64) -> (Box<[Op]>, Box<[u8]>) {
+    let mut ops = Vec::new();
+    let mut op_index = Vec::new();
+    let mut i = 0;
+    while i < ins.len() {
+        let (a, b, c) = ins[i];
+        let op = find_idiom(&ins[i..], start + 24 * i as u64).unwrap_or(Op::Subleq(a, b, c));
+        op_index.extend(std::iter::repeat_n(ops.len() as u8, op.len() as usize));
+        ops.push(op);
+        i += op.len() as usize;
+    }
+
+    (ops.into_boxed_slice(), op_index.into_boxed_slice())
+}
+
+// Decodes the code the CPU runs into blocks keyed by their starting EIP, so that each
+// instruction is read from memory once instead of every time it is executed.
+//
+// Every byte covered by a block is tracked, and a write into one either patches the decoded
+// operand in place (the common case of SUBLEQ code rewriting its own operands to access
+// memory indirectly) or drops the block so that it is decoded again the next time it runs.
+// Only EIPs aligned to 8 bytes get blocks, anything else goes through the plain path.
+//
+// Optionally, common SUBLEQ idioms in the decoded code are fused and executed natively as a
+// whole. Writes into a fused idiom always drop its block.
+pub struct BlockCache {
+    blocks: Vec<Block>,
+    free_ids: Vec<u32>,
+    // Block id + 1 for every aligned EIP starting a block, 0 if there is none
+    entries: Vec<Option<Box<[u32; SLOTS_PER_PAGE]>>>,
+    // Ids of the blocks overlapping each page
+    page_blocks: Vec<Vec<u32>>,
+    // One bit per 8 bytes of memory covered by any block
+    covered: Vec<u64>,
+    // Bounds of all the memory ever covered by a block
+    code_start: u64,
+    code_end: u64,
+    // Instructions touching anything at or above this are device accesses
+    fast_limit: u64,
+    mem_len: usize,
+    idioms: bool,
+}
+
+impl BlockCache {
+    pub fn new(mem: &[u8], idioms: bool) -> Self {
+        let page_count = (mem.len() >> PAGE_SHIFT) + 1;
+        BlockCache {
+            blocks: Vec::new(),
+            free_ids: Vec::new(),
+            entries: (0..page_count).map(|_| None).collect(),
+            page_blocks: vec![Vec::new(); page_count],
+            covered: vec![0; (mem.len() >> 9) + 1],
+            code_start: u64::MAX,
+            code_end: 0,
+            fast_limit: std::cmp::min(IO_START, mem.len().saturating_sub(8) as u64),
+            mem_len: mem.len(),
+            idioms,
+        }
+    }
+
+    // Whether the 8 bytes written at addr overlap a block
+    #[inline(always)]
+    fn is_covered(&self, addr: u64) -> bool {
+        if addr + 8 <= self.code_start || addr >= self.code_end {
+            return false;
+        }
+
+        let first = (addr >> 3) as usize;
+        let last = ((addr + 7) >> 3) as usize;
+        (self.covered[first >> 6] >> (first & 63)) & 1 != 0
+            || (self.covered[last >> 6] >> (last & 63)) & 1 != 0
+    }
+
+    #[inline(always)]
+    fn block_at(&mut self, mem: &[u8], eip: u64) -> Option<u32> {
+        if !eip.is_multiple_of(8) {
+            return None;
+        }
+
+        let slot = (eip >> 3) as usize % SLOTS_PER_PAGE;
+        if let Some(entries) = &self.entries[(eip >> PAGE_SHIFT) as usize] {
+            if entries[slot] != 0 {
+                return Some(entries[slot] - 1);
+            }
+        }
+
+        self.decode(mem, eip)
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn decode(&mut self, mem: &[u8], start: u64) -> Option<u32> {
+        let mut ins = Vec::new();
+        let mut eip = start;
+        while ins.len() < MAX_BLOCK_LEN && eip + 24 <= self.fast_limit {
+            let a = mem::read(mem, eip as usize) as u64;
+            let b = mem::read(mem, (eip + 8) as usize) as u64;
+            let c = mem::read(mem, (eip + 16) as usize) as u64;
+
+            // Device accesses go through the plain path
+            if a >= self.fast_limit || b >= self.fast_limit {
+                break;
+            }
+
+            ins.push((a, b, c));
+            eip += 24;
+            if a == b && c != eip {
+                break;
+            }
+        }
+
+        if ins.is_empty() {
+            return None;
+        }
+
+        let (ops, op_index) = if self.idioms {
+            fuse(&ins, start)
+        } else {
+            (Box::default(), Box::default())
+        };
+        let block = Block {
+            start,
+            end: eip,
+            ins: ins.into_boxed_slice(),
+            ops,
+            op_index,
+        };
+
+        let id = match self.free_ids.pop() {
+            Some(id) => {
+                self.blocks[id as usize] = block;
+                id
+            }
+            None => {
+                self.blocks.push(block);
+                (self.blocks.len() - 1) as u32
+            }
+        };
+
+        for page in (start >> PAGE_SHIFT)..=((eip - 1) >> PAGE_SHIFT) {
+            self.page_blocks[page as usize].push(id);
+        }
+        for slot in (start >> 3)..(eip >> 3) {
+            self.covered[(slot >> 6) as usize] |= 1 << (slot & 63);
+        }
+        self.code_start = std::cmp::min(self.code_start, start);
+        self.code_end = std::cmp::max(self.code_end, eip);
+        self.entries[(start >> PAGE_SHIFT) as usize]
+            .get_or_insert_with(|| Box::new([0; SLOTS_PER_PAGE]))
+            [(start >> 3) as usize % SLOTS_PER_PAGE] = id + 1;
+
+        Some(id)
+    }
+
+    // Updates a block after the 8 bytes at addr were overwritten, returns false if the block
+    // has to be decoded again instead
+    fn patch(&mut self, mem: &[u8], id: u32, addr: u64) -> bool {
+        let fast_limit = self.fast_limit;
+        let block = &mut self.blocks[id as usize];
+        if addr < block.start || !(addr - block.start).is_multiple_of(8) {
+            return false;
+        }
+
+        let word = ((addr - block.start) / 8) as usize;
+        let (i, field) = (word / 3, word % 3);
+        let val = mem::read(mem, addr as usize) as u64;
+        match field {
+            0 if val < fast_limit => block.ins[i].0 = val,
+            1 if val < fast_limit => block.ins[i].1 = val,
+            2 => block.ins[i].2 = val,
+            _ => return false,
+        }
+
+        if !block.ops.is_empty() {
+            let (a, b, c) = block.ins[i];
+            let op = &mut block.ops[block.op_index[i] as usize];
+            if !matches!(op, Op::Subleq(..)) {
+                return false;
+            }
+            *op = Op::Subleq(a, b, c);
+        }
+
+        true
+    }
+
+    fn remove(&mut self, id: u32, dirty_pages: &mut Vec<u64>) {
+        let block = &self.blocks[id as usize];
+        let (start, end) = (block.start, block.end);
+
+        for page in (start >> PAGE_SHIFT)..=((end - 1) >> PAGE_SHIFT) {
+            self.page_blocks[page as usize].retain(|&other| other != id);
+            dirty_pages.push(page);
+        }
+        self.entries[(start >> PAGE_SHIFT) as usize]
+            .as_mut()
+            .unwrap()[(start >> 3) as usize % SLOTS_PER_PAGE] = 0;
+
+        self.blocks[id as usize].ins = Box::new([]);
+        self.blocks[id as usize].ops = Box::new([]);
+        self.free_ids.push(id);
+    }
+
+    // Rebuilds the coverage bits of a page from the blocks still overlapping it
+    fn recompute_coverage(&mut self, page: u64) {
+        let page_start = page << PAGE_SHIFT;
+        let page_end = page_start + (1 << PAGE_SHIFT);
+        for slot in (page_start >> 3)..(page_end >> 3) {
+            if let Some(word) = self.covered.get_mut((slot >> 6) as usize) {
+                *word &= !(1 << (slot & 63));
+            }
+        }
+
+        for &id in &self.page_blocks[page as usize] {
+            let block = &self.blocks[id as usize];
+            let start = std::cmp::max(block.start, page_start);
+            let end = std::cmp::min(block.end, page_end);
+            for slot in (start >> 3)..(end >> 3) {
+                self.covered[(slot >> 6) as usize] |= 1 << (slot & 63);
+            }
+        }
+    }
+
+    // Brings the blocks overlapping the 8 bytes written at addr up to date
+    #[cold]
+    #[inline(never)]
+    fn invalidate(&mut self, mem: &[u8], addr: u64) {
+        let mut dirty_pages = Vec::new();
+        let mut pages = vec![addr >> PAGE_SHIFT];
+        if (addr + 7) >> PAGE_SHIFT != addr >> PAGE_SHIFT {
+            pages.push((addr + 7) >> PAGE_SHIFT);
+        }
+
+        for page in pages {
+            for id in self.page_blocks[page as usize].clone() {
+                let block = &self.blocks[id as usize];
+                if block.start >= addr + 8 || block.end <= addr || self.patch(mem, id, addr) {
+                    continue;
+                }
+
+                self.remove(id, &mut dirty_pages);
+            }
+        }
+
+        dirty_pages.sort_unstable();
+        dirty_pages.dedup();
+        for page in dirty_pages {
+            self.recompute_coverage(page);
+        }
+    }
+
+    // Must be called after anything else wrote the 8 bytes at addr
+    pub fn memory_written(&mut self, mem: &[u8], addr: u64) {
+        if addr < self.fast_limit && self.is_covered(addr) {
+            self.invalidate(mem, addr);
+        }
+    }
+
+    // Runs the block with the given id from its start. A write into decoded code stops the
+    // block right after the instruction doing it, so that the change is picked up before
+    // anything else runs, and is returned. A block that jumps back to its own start runs again
+    // without being looked up.
+    #[inline(always)]
+    fn run_block(
+        &self,
+        mem: &mut [u8],
+        id: u32,
+        cur: &mut u64,
+        executed: &mut u64,
+        budget: u64,
+    ) -> [Option<u64>; 2] {
+        let block = &self.blocks[id as usize];
+        loop {
+            let len = std::cmp::min(block.ins.len() as u64, budget - *executed) as usize;
+            for &(a_addr, b_addr, c_addr) in &block.ins[..len] {
+                *executed += 1;
+
+                // SAFETY: decode() only accepts operands below fast_limit, which is at most
+                // mem.len() - 8
+                let a_val = unsafe {
+                    let a_val = mem::read_unchecked(mem, a_addr as usize)
+                        .wrapping_sub(mem::read_unchecked(mem, b_addr as usize));
+                    mem::write_unchecked(mem, a_addr as usize, a_val);
+                    a_val
+                };
+
+                // Most instructions fall through whichever way they go, so there is nothing
+                // worth predicting until cur is compared below
+                let fallthrough = *cur + 24;
+                *cur = std::hint::select_unpredictable(a_val <= 0, c_addr, fallthrough);
+                if self.is_covered(a_addr) {
+                    return [Some(a_addr), None];
+                }
+                if *cur != fallthrough {
+                    break;
+                }
+            }
+
+            if *cur != block.start || *executed == budget {
+                return [None, None];
+            }
+        }
+    }
+
+    // Same as run_block(), for blocks with idioms. Returns the addresses written into decoded
+    // code, an idiom can write two.
+    #[inline(always)]
+    fn run_ops(
+        &self,
+        mem: &mut [u8],
+        id: u32,
+        cur: &mut u64,
+        executed: &mut u64,
+        budget: u64,
+    ) -> [Option<u64>; 2] {
+        let block = &self.blocks[id as usize];
+        let covered = |addr: u64| self.is_covered(addr).then_some(addr);
+        loop {
+            for &op in &block.ops {
+                if budget - *executed < op.len() {
+                    return [None, None];
+                }
+                *executed += op.len();
+
+                let fallthrough = *cur + 24 * op.len();
+                // SAFETY: decode() only accepts operands below fast_limit, which is at most
+                // mem.len() - 8
+                unsafe {
+                    match op {
+                        Op::Subleq(a_addr, b_addr, c_addr) => {
+                            let a_val = mem::read_unchecked(mem, a_addr as usize)
+                                .wrapping_sub(mem::read_unchecked(mem, b_addr as usize));
+                            mem::write_unchecked(mem, a_addr as usize, a_val);
+                            *cur = std::hint::select_unpredictable(a_val <= 0, c_addr, fallthrough);
+                            if self.is_covered(a_addr) {
+                                return [Some(a_addr), None];
+                            }
+                        }
+                        Op::Add { z, a, b, c } => {
+                            let z_val = mem::read_unchecked(mem, z as usize)
+                                .wrapping_sub(mem::read_unchecked(mem, a as usize));
+                            let b_val = mem::read_unchecked(mem, b as usize).wrapping_sub(z_val);
+                            mem::write_unchecked(mem, b as usize, b_val);
+                            mem::write_unchecked(mem, z as usize, 0);
+                            *cur = c;
+                            if self.is_covered(b) || self.is_covered(z) {
+                                return [covered(b), covered(z)];
+                            }
+                        }
+                        Op::Move { z, a, b, c } => {
+                            let z_val = mem::read_unchecked(mem, z as usize)
+                                .wrapping_sub(mem::read_unchecked(mem, a as usize));
+                            mem::write_unchecked(mem, b as usize, z_val.wrapping_neg());
+                            mem::write_unchecked(mem, z as usize, 0);
+                            *cur = c;
+                            if self.is_covered(b) || self.is_covered(z) {
+                                return [covered(b), covered(z)];
+                            }
+                        }
+                    }
+                }
+
+                if *cur != fallthrough {
+                    break;
+                }
+            }
+
+            if *cur != block.start || *executed == budget {
+                return [None, None];
+            }
+        }
+    }
+
+    // Same as exec::run(), but executes decoded blocks where possible
+    pub fn run(&mut self, mem: &mut [u8], eip: &mut u64, budget: u64) -> (u64, Event) {
+        assert_eq!(
+            self.mem_len,
+            mem.len(),
+            "BlockCache used with a different memory"
+        );
+
+        let mut cur = *eip;
+        let mut executed = 0;
+        let event = loop {
+            if executed == budget {
+                break Event::Budget;
+            }
+
+            if !exec::eip_in_bounds(mem, cur) {
+                break Event::EipOutOfBounds;
+            }
+
+            if let Some(id) = self.block_at(mem, cur) {
+                let before = executed;
+                let written = if self.idioms {
+                    self.run_ops(mem, id, &mut cur, &mut executed, budget)
+                } else {
+                    self.run_block(mem, id, &mut cur, &mut executed, budget)
+                };
+
+                for addr in written.into_iter().flatten() {
+                    self.invalidate(mem, addr);
+                }
+                // Only an idiom that doesn't fit in the budget runs nothing, its first
+                // instruction goes through the plain path instead
+                if executed != before {
+                    continue;
+                }
+            }
+
+            executed += 1;
+
+            // SAFETY: eip_in_bounds() guarantees cur + 24 <= mem.len()
+            let (a_addr, b_addr, c_addr) = unsafe {
+                (
+                    mem::read_unchecked(mem, cur as usize) as u64,
+                    mem::read_unchecked(mem, (cur + 8) as usize) as u64,
+                    mem::read_unchecked(mem, (cur + 16) as usize) as u64,
+                )
+            };
+
+            if a_addr >= self.fast_limit || b_addr >= self.fast_limit {
+                cur = exec::step(mem, cur).next_eip();
+                if a_addr < self.fast_limit && self.is_covered(a_addr) {
+                    self.invalidate(mem, a_addr);
+                }
+                break Event::DeviceAccess;
+            }
+
+            // SAFETY: Both addresses are below fast_limit, which is at most mem.len() - 8
+            let a_val = unsafe {
+                let a_val = mem::read_unchecked(mem, a_addr as usize)
+                    .wrapping_sub(mem::read_unchecked(mem, b_addr as usize));
+                mem::write_unchecked(mem, a_addr as usize, a_val);
+                a_val
+            };
+
+            if self.is_covered(a_addr) {
+                self.invalidate(mem, a_addr);
+            }
+
+            cur = if a_val <= 0 { c_addr } else { cur + 24 };
+        };
+
+        *eip = cur;
+        (executed, event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const Z: u64 = 0x1000;
+    const A: u64 = 0x1008;
+    const B: u64 = 0x1010;
+
+    #[test]
+    fn idioms_are_found() {
+        let add = [(Z, A, 24), (B, Z, 48), (Z, Z, 0x200)];
+        assert_eq!(
+            find_idiom(&add, 0),
+            Some(Op::Add {
+                z: Z,
+                a: A,
+                b: B,
+                c: 0x200
+            })
+        );
+
+        let mov = [(B, B, 24), (Z, A, 48), (B, Z, 72), (Z, Z, 0x200)];
+        assert_eq!(
+            find_idiom(&mov, 0),
+            Some(Op::Move {
+                z: Z,
+                a: A,
+                b: B,
+                c: 0x200
+            })
+        );
+
+        // The add at the end of the move is found when the clear can't be fused
+        let (ops, op_index) = fuse(&[(B, B, 0x300), (Z, A, 48), (B, Z, 72), (Z, Z, 0x200)], 0);
+        assert_eq!(ops[0], Op::Subleq(B, B, 0x300));
+        assert_eq!(
+            ops[1],
+            Op::Add {
+                z: Z,
+                a: A,
+                b: B,
+                c: 0x200
+            }
+        );
+        assert_eq!(&*op_index, &[0, 1, 1, 1]);
+    }
+
+    #[test]
+    fn overlapping_idioms_are_not_fused() {
+        // Operands sharing a word
+        assert_eq!(
+            find_idiom(&[(Z, A, 24), (A, Z, 48), (Z, Z, 0x200)], 0),
+            None
+        );
+        assert_eq!(
+            find_idiom(&[(Z, Z + 4, 24), (B, Z, 48), (Z, Z, 0x200)], 0),
+            None
+        );
+        // A write into the idiom itself
+        assert_eq!(
+            find_idiom(&[(Z, A, 24), (40, Z, 48), (Z, Z, 0x200)], 0),
+            None
+        );
+        // Not the pattern
+        assert_eq!(
+            find_idiom(&[(Z, A, 24), (B, Z, 0x300), (Z, Z, 0x200)], 0),
+            None
+        );
+    }
+}
@@ -0,0 +1,267 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+};
+
+use crate::pdb::DebugData;
+
+// A .cov file is laid out as:
+//   magic (8 bytes) | version (u32 LE) | bin hash (u64 LE) | count (u64 LE) | records
+// with every record being three u64 LE: eip | taken | not taken, sorted by EIP
+pub const COVERAGE_MAGIC: [u8; 8] = *b"NTCOV\0\0\0";
+pub const COVERAGE_VERSION: u32 = 2;
+
+const PAGE_SHIFT: u32 = 12;
+const SLOTS_PER_PAGE: usize = 1 << (PAGE_SHIFT - 3);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct InstructionCoverage {
+    pub eip: u64,
+    // Number of times the branch was taken and not taken
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+impl InstructionCoverage {
+    pub fn executions(&self) -> u64 {
+        self.taken + self.not_taken
+    }
+}
+
+// Counts how often the branch of every executed instruction was taken and not taken. Nearly
+// all code is aligned to 8 bytes, so that gets pages of counters allocated on first use, and
+// the rest goes into a map.
+pub struct CoverageRecorder {
+    pages: Vec<Option<Box<[[u64; 2]; SLOTS_PER_PAGE]>>>,
+    unaligned: HashMap<u64, [u64; 2]>,
+}
+
+impl CoverageRecorder {
+    pub fn new(mem_len: usize) -> Self {
+        CoverageRecorder {
+            pages: (0..(mem_len >> PAGE_SHIFT) + 1).map(|_| None).collect(),
+            unaligned: HashMap::new(),
+        }
+    }
+
+    #[inline(always)]
+    pub fn record(&mut self, eip: u64, branch_taken: bool) {
+        let counters = if eip.is_multiple_of(8) {
+            &mut self.pages[(eip >> PAGE_SHIFT) as usize]
+                .get_or_insert_with(|| Box::new([[0; 2]; SLOTS_PER_PAGE]))
+                [(eip >> 3) as usize % SLOTS_PER_PAGE]
+        } else {
+            self.unaligned.entry(eip).or_insert([0; 2])
+        };
+        counters[branch_taken as usize] += 1;
+    }
+
+    pub fn instructions(&self) -> Vec<InstructionCoverage> {
+        let to_coverage = |eip, counters: &[u64; 2]| InstructionCoverage {
+            eip,
+            taken: counters[1],
+            not_taken: counters[0],
+        };
+
+        let mut ret: Vec<InstructionCoverage> = self
+            .unaligned
+            .iter()
+            .map(|(&eip, counters)| to_coverage(eip, counters))
+            .collect();
+        for (page, counters) in self.pages.iter().enumerate() {
+            let Some(counters) = counters else {
+                continue;
+            };
+
+            for (slot, counters) in counters.iter().enumerate() {
+                if counters[0] + counters[1] != 0 {
+                    let eip = ((page << PAGE_SHIFT) + slot * 8) as u64;
+                    ret.push(to_coverage(eip, counters));
+                }
+            }
+        }
+
+        ret.sort_unstable_by_key(|ins| ins.eip);
+        ret
+    }
+}
+
+// Adds up the coverage recorded by several CPUs
+pub fn merge(recorded: impl Iterator<Item = Vec<InstructionCoverage>>) -> Vec<InstructionCoverage> {
+    let mut merged: HashMap<u64, InstructionCoverage> = HashMap::new();
+    for ins in recorded.flatten() {
+        let entry = merged.entry(ins.eip).or_insert(InstructionCoverage {
+            eip: ins.eip,
+            taken: 0,
+            not_taken: 0,
+        });
+        entry.taken += ins.taken;
+        entry.not_taken += ins.not_taken;
+    }
+
+    let mut ret: Vec<InstructionCoverage> = merged.into_values().collect();
+    ret.sort_unstable_by_key(|ins| ins.eip);
+    ret
+}
+
+pub struct CoverageData {
+    pub bin_hash: u64,
+    // Sorted by EIP
+    pub instructions: Vec<InstructionCoverage>,
+}
+
+impl CoverageData {
+    pub fn write(&self, path: &str) {
+        let mut out = BufWriter::new(File::create(path).unwrap());
+        out.write_all(&COVERAGE_MAGIC).unwrap();
+        out.write_all(&COVERAGE_VERSION.to_le_bytes()).unwrap();
+        out.write_all(&self.bin_hash.to_le_bytes()).unwrap();
+        out.write_all(&(self.instructions.len() as u64).to_le_bytes())
+            .unwrap();
+        for ins in &self.instructions {
+            out.write_all(&ins.eip.to_le_bytes()).unwrap();
+            out.write_all(&ins.taken.to_le_bytes()).unwrap();
+            out.write_all(&ins.not_taken.to_le_bytes()).unwrap();
+        }
+        out.flush().unwrap();
+    }
+
+    pub fn read(path: &str) -> Result<Self, String> {
+        let file = File::open(path).map_err(|e| format!("Failed to open {path}: {e}"))?;
+        let mut inp = BufReader::new(file);
+
+        let mut header = [0u8; 8 + 4 + 8 + 8];
+        inp.read_exact(&mut header)
+            .map_err(|_| format!("{path} is too short to be a .cov file"))?;
+
+        if header[0..8] != COVERAGE_MAGIC {
+            return Err(format!("{path} is not a .cov file"));
+        }
+
+        let version = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        if version != COVERAGE_VERSION {
+            return Err(format!(
+                "Unsupported .cov version {version} (expected {COVERAGE_VERSION})"
+            ));
+        }
+
+        let bin_hash = u64::from_le_bytes(header[12..20].try_into().unwrap());
+        let count = u64::from_le_bytes(header[20..28].try_into().unwrap());
+        let mut instructions = Vec::new();
+        for _ in 0..count {
+            let mut data = [0u8; 24];
+            inp.read_exact(&mut data)
+                .map_err(|_| format!("{path} is truncated"))?;
+            instructions.push(InstructionCoverage {
+                eip: u64::from_le_bytes(data[0..8].try_into().unwrap()),
+                taken: u64::from_le_bytes(data[8..16].try_into().unwrap()),
+                not_taken: u64::from_le_bytes(data[16..24].try_into().unwrap()),
+            });
+        }
+
+        Ok(CoverageData {
+            bin_hash,
+            instructions,
+        })
+    }
+}
+
+// Lines that assemble to nothing or only to data can never be executed, so they are left out
+// of the coverage. The last line ends at program_end, one past the last byte of the program.
+pub fn is_code_line(debug_data: &DebugData, line: usize, program_end: u64) -> bool {
+    let end = match debug_data.offsets.get(line + 1) {
+        Some(&(end, _)) => end,
+        None => program_end,
+    };
+
+    let (start, text) = &debug_data.offsets[line];
+    let first_token = text.split_whitespace().next().unwrap_or("");
+    end > *start && first_token != "raw" && first_token != "raw_ref"
+}
+
+// Number of distinct EIPs executed on every line, anything at or past program_end is not part
+// of the program
+pub fn hits_per_line(
+    debug_data: &DebugData,
+    instructions: &[InstructionCoverage],
+    program_end: u64,
+) -> Vec<u64> {
+    let mut ret = vec![0; debug_data.offsets.len()];
+    for ins in instructions {
+        if ins.eip >= program_end {
+            continue;
+        }
+
+        ret[crate::pdb::find_line(debug_data, ins.eip)] += 1;
+    }
+
+    ret
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BranchKind {
+    // c is the next instruction, so both ways lead to the same place
+    FallThrough,
+    // a and b are the same, so the branch is always taken
+    Jump,
+    Conditional,
+}
+
+// Classifies the instruction at EIP as loaded from the .bin. Code outside of the .bin can't be
+// looked at after the fact, so it is assumed to be conditional.
+pub fn branch_kind(bin: &[u8], eip: u64) -> BranchKind {
+    if eip.saturating_add(24) > bin.len() as u64 {
+        return BranchKind::Conditional;
+    }
+
+    let a = crate::mem::read(bin, eip as usize);
+    let b = crate::mem::read(bin, (eip + 8) as usize);
+    let c = crate::mem::read(bin, (eip + 16) as usize) as u64;
+    if c == eip + 24 {
+        BranchKind::FallThrough
+    } else if a == b {
+        BranchKind::Jump
+    } else {
+        BranchKind::Conditional
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> DebugData {
+        DebugData {
+            offsets: vec![
+                (0, "# comment".to_owned()),
+                (0, "clear x".to_owned()),
+                (24, "raw 5".to_owned()),
+                (32, "jump loop".to_owned()),
+            ],
+        }
+    }
+
+    #[test]
+    fn code_lines() {
+        let debug_data = sample();
+        let code: Vec<bool> = (0..4).map(|i| is_code_line(&debug_data, i, 56)).collect();
+        assert_eq!(code, [false, true, false, true]);
+
+        // The last line is empty if the program ends where it starts
+        assert!(!is_code_line(&debug_data, 3, 32));
+    }
+
+    #[test]
+    fn hits_include_last_line() {
+        let instructions: Vec<InstructionCoverage> = [0, 32, 32 + 24]
+            .iter()
+            .map(|&eip| InstructionCoverage {
+                eip,
+                taken: 1,
+                not_taken: 0,
+            })
+            .collect();
+        assert_eq!(hits_per_line(&sample(), &instructions, 56), [0, 1, 0, 1]);
+    }
+}
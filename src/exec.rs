@@ -0,0 +1,219 @@
+use crate::mem;
+
+// Memory-mapped devices (serial port, CPU control blocks) live at and above this address
+pub const IO_START: u64 = 0x13ED0000;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Event {
+    // The instruction budget ran out
+    Budget,
+    // The last executed instruction read or wrote device memory
+    DeviceAccess,
+    // EIP points outside of memory, nothing was executed there
+    EipOutOfBounds,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Executed {
+    pub eip: u64,
+    pub a_addr: u64,
+    pub b_addr: u64,
+    pub c_addr: u64,
+    pub a_before: i64,
+    pub b_val: i64,
+}
+
+impl Executed {
+    pub fn a_after(&self) -> i64 {
+        self.a_before.wrapping_sub(self.b_val)
+    }
+
+    pub fn branch_taken(&self) -> bool {
+        self.a_after() <= 0
+    }
+
+    pub fn next_eip(&self) -> u64 {
+        if self.branch_taken() {
+            self.c_addr
+        } else {
+            self.eip + 24
+        }
+    }
+
+    pub fn accesses_device(&self) -> bool {
+        self.a_addr >= IO_START || self.b_addr >= IO_START
+    }
+}
+
+pub fn eip_in_bounds(mem: &[u8], eip: u64) -> bool {
+    eip.checked_add(24)
+        .is_some_and(|end| end <= mem.len() as u64)
+}
+
+// Executes a single instruction, the caller must make sure that EIP is within memory
+#[inline(always)]
+pub fn step(mem: &mut [u8], eip: u64) -> Executed {
+    let a_addr = mem::read(mem, eip as usize) as u64;
+    let b_addr = mem::read(mem, (eip + 8) as usize) as u64;
+    let c_addr = mem::read(mem, (eip + 16) as usize) as u64;
+
+    let a_before = mem::read(mem, a_addr as usize);
+    let b_val = mem::read(mem, b_addr as usize);
+    mem::write(
+        mem,
+        a_addr as usize,
+        &i64::to_be_bytes(a_before.wrapping_sub(b_val)),
+    );
+
+    Executed {
+        eip,
+        a_addr,
+        b_addr,
+        c_addr,
+        a_before,
+        b_val,
+    }
+}
+
+// Executes instructions starting from eip until the budget runs out or an event occurs,
+// leaving eip at the next instruction. Returns the number of instructions executed.
+pub fn run(mem: &mut [u8], eip: &mut u64, budget: u64) -> (u64, Event) {
+    // Instructions touching anything at or above this go through step(), which takes care
+    // of bounds checking
+    let fast_limit = std::cmp::min(IO_START, mem.len().saturating_sub(8) as u64);
+
+    let mut cur = *eip;
+    let mut executed = 0;
+    let event = loop {
+        if executed == budget {
+            break Event::Budget;
+        }
+
+        if !eip_in_bounds(mem, cur) {
+            break Event::EipOutOfBounds;
+        }
+
+        executed += 1;
+
+        // SAFETY: eip_in_bounds() guarantees cur + 24 <= mem.len()
+        let (a_addr, b_addr, c_addr) = unsafe {
+            (
+                mem::read_unchecked(mem, cur as usize) as u64,
+                mem::read_unchecked(mem, (cur + 8) as usize) as u64,
+                mem::read_unchecked(mem, (cur + 16) as usize) as u64,
+            )
+        };
+
+        if a_addr >= fast_limit || b_addr >= fast_limit {
+            cur = step(mem, cur).next_eip();
+            break Event::DeviceAccess;
+        }
+
+        // SAFETY: Both addresses are below fast_limit, which is at most mem.len() - 8
+        let a_val = unsafe {
+            let a_val = mem::read_unchecked(mem, a_addr as usize)
+                .wrapping_sub(mem::read_unchecked(mem, b_addr as usize));
+            mem::write_unchecked(mem, a_addr as usize, a_val);
+            a_val
+        };
+
+        cur = if a_val <= 0 { c_addr } else { cur + 24 };
+    };
+
+    *eip = cur;
+    (executed, event)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_word(mem: &mut [u8], addr: u64, val: u64) {
+        mem::write(mem, addr as usize, &val.to_be_bytes());
+    }
+
+    // A program in 0x100 bytes of memory, so that the last word at 0xF8 is right at the limit
+    // run() handles itself. It touches that word twice, then jumps to the last instruction
+    // that fits, which branches out of memory.
+    fn boundary_program() -> Vec<u8> {
+        let mut mem = vec![0; 0x100];
+        for (i, (a, b, c)) in [(0xF8, 0xC0, 0x300), (0xC8, 0xF8, 0x300), (0xD0, 0xD0, 0xE8)]
+            .into_iter()
+            .enumerate()
+        {
+            let eip = 24 * i as u64;
+            write_word(&mut mem, eip, a);
+            write_word(&mut mem, eip + 8, b);
+            write_word(&mut mem, eip + 16, c);
+        }
+        // The instruction at 0xE8 overlaps these, 0xC0 -= 0xC8 and go to 0xF0
+        write_word(&mut mem, 0xE8, 0xC0);
+        write_word(&mut mem, 0xF0, 0xC8);
+        write_word(&mut mem, 0xF8, 0xF0);
+        write_word(&mut mem, 0xC8, 0x1000);
+        mem
+    }
+
+    // Runs run() with the given budget until EIP leaves memory, and checks that every call
+    // leaves memory and EIP the same as calling step() as many times
+    fn compare_with_step(mut run_mem: Vec<u8>, budget: u64) -> Vec<(u64, Event)> {
+        let mut step_mem = run_mem.clone();
+        let mut run_eip = 0;
+        let mut step_eip = 0;
+        let mut events = Vec::new();
+        loop {
+            let (executed, event) = run(&mut run_mem, &mut run_eip, budget);
+            for _ in 0..executed {
+                assert!(eip_in_bounds(&step_mem, step_eip));
+                step_eip = step(&mut step_mem, step_eip).next_eip();
+            }
+            assert_eq!(run_eip, step_eip);
+            assert!(run_mem == step_mem);
+
+            events.push((executed, event));
+            if event == Event::EipOutOfBounds {
+                assert!(!eip_in_bounds(&step_mem, step_eip));
+                return events;
+            }
+        }
+    }
+
+    #[test]
+    fn run_matches_step() {
+        // Instructions touching the last word are left to step() and reported like device
+        // accesses
+        assert_eq!(
+            compare_with_step(boundary_program(), 100),
+            [
+                (1, Event::DeviceAccess),
+                (1, Event::DeviceAccess),
+                (2, Event::EipOutOfBounds)
+            ]
+        );
+        assert_eq!(
+            compare_with_step(boundary_program(), 1),
+            [
+                (1, Event::DeviceAccess),
+                (1, Event::DeviceAccess),
+                (1, Event::Budget),
+                (1, Event::Budget),
+                (0, Event::EipOutOfBounds)
+            ]
+        );
+    }
+
+    #[test]
+    fn eip_out_of_bounds() {
+        let mut mem = boundary_program();
+        for start in [0xE9, 0x100, u64::MAX - 8] {
+            let mut eip = start;
+            assert_eq!(run(&mut mem, &mut eip, 10), (0, Event::EipOutOfBounds));
+            assert_eq!(eip, start);
+        }
+        assert!(mem == boundary_program());
+
+        let mut eip = 0xE8;
+        assert_eq!(run(&mut mem, &mut eip, 10), (1, Event::EipOutOfBounds));
+        assert_eq!(eip, 0xF0);
+    }
+}
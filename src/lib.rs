@@ -1 +1,11 @@
+pub mod assembler;
+pub mod block;
+pub mod coverage;
+pub mod exec;
+pub mod loader;
+pub mod mem;
 pub mod pdb;
+pub mod perf;
+pub mod smc;
+pub mod snapshot;
+pub mod trace;
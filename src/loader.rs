@@ -0,0 +1,373 @@
+use std::path::Path;
+
+use crate::{assembler, pdb::DebugData, perf};
+
+// A program or blob split into the pieces of memory it fills, along with where execution
+// should start if the file says so, and the source lines of assembled programs
+#[derive(Default)]
+pub struct Image {
+    pub segments: Vec<(u64, Vec<u8>)>,
+    pub entry: Option<u64>,
+    pub debug_data: Option<DebugData>,
+}
+
+// Extensions tried after the base path, in order. Sources only get assembled when there is
+// nothing built from them.
+pub const PROGRAM_EXTENSIONS: [&str; 10] = [
+    "bin", "ihex", "ihx", "srec", "s19", "s28", "s37", "hex2", "hex1", "hex0",
+];
+
+impl Image {
+    fn push(&mut self, addr: u64, data: &[u8]) {
+        match self.segments.last_mut() {
+            Some((start, last)) if *start + last.len() as u64 == addr => {
+                last.extend_from_slice(data)
+            }
+            _ => self.segments.push((addr, data.to_vec())),
+        }
+    }
+
+    // One past the highest address filled
+    pub fn end(&self) -> u64 {
+        self.segments
+            .iter()
+            .map(|(addr, data)| addr + data.len() as u64)
+            .max()
+            .unwrap_or(0)
+    }
+
+    // Everything from 0 to end() as it ends up in memory, with the gaps left zeroed
+    pub fn flatten(&self) -> Vec<u8> {
+        let mut ret = vec![0; self.end() as usize];
+        for (addr, data) in &self.segments {
+            ret[*addr as usize..*addr as usize + data.len()].copy_from_slice(data);
+        }
+        ret
+    }
+
+    // Identifies the program in .perf, .cov and .trace files. This is the hash of the .bin
+    // that --write-bin would write, whatever format the program was loaded from.
+    pub fn hash(&self) -> u64 {
+        perf::hash_bytes(&self.flatten())
+    }
+
+    // Copies every segment into mem, moved up by offset
+    pub fn load_into(&self, mem: &mut [u8], offset: u64) -> Result<(), String> {
+        for (addr, data) in &self.segments {
+            let start = addr.checked_add(offset).filter(|start| {
+                start
+                    .checked_add(data.len() as u64)
+                    .is_some_and(|end| end <= mem.len() as u64)
+            });
+            let Some(start) = start else {
+                return Err(format!(
+                    "Segment at {:#x} ({:#x} bytes) is outside of memory",
+                    addr.wrapping_add(offset),
+                    data.len()
+                ));
+            };
+            mem[start as usize..start as usize + data.len()].copy_from_slice(data);
+        }
+
+        Ok(())
+    }
+}
+
+fn hex_bytes(line: &str) -> Option<Vec<u8>> {
+    if !line.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..line.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(line.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn be_number(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0, |acc, &b| acc << 8 | b as u64)
+}
+
+// Intel HEX, with data (00), end of file (01), extended segment address (02), start segment
+// address (03), extended linear address (04) and start linear address (05) records
+pub fn parse_intel_hex(text: &str) -> Result<Image, String> {
+    let mut image = Image::default();
+    let mut base = 0;
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let invalid = |reason: &str| format!("Line {}: {reason}", i + 1);
+        let bytes = line
+            .strip_prefix(':')
+            .and_then(hex_bytes)
+            .ok_or_else(|| invalid("Not an Intel HEX record"))?;
+        if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+            return Err(invalid("Wrong record length"));
+        }
+        if bytes.iter().fold(0u8, |acc, &b| acc.wrapping_add(b)) != 0 {
+            return Err(invalid("Bad checksum"));
+        }
+
+        let data = &bytes[4..bytes.len() - 1];
+        match (bytes[3], data.len()) {
+            (0x00, _) => image.push(base + be_number(&bytes[1..3]), data),
+            (0x01, _) => break,
+            (0x02, 2) => base = be_number(data) << 4,
+            (0x03, 4) => image.entry = Some((be_number(&data[..2]) << 4) + be_number(&data[2..])),
+            (0x04, 2) => base = be_number(data) << 16,
+            (0x05, 4) => image.entry = Some(be_number(data)),
+            _ => return Err(invalid("Unsupported record")),
+        }
+    }
+
+    Ok(image)
+}
+
+// Motorola S-records. S1/S2/S3 hold data with 2/3/4-byte addresses and S9/S8/S7 the entry
+// point, header (S0) and count (S5/S6) records are skipped.
+pub fn parse_srecord(text: &str) -> Result<Image, String> {
+    let mut image = Image::default();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let invalid = |reason: &str| format!("Line {}: {reason}", i + 1);
+        let (kind, bytes) = line
+            .strip_prefix('S')
+            .and_then(|rest| {
+                let mut chars = rest.chars();
+                Some((chars.next()?, hex_bytes(chars.as_str())?))
+            })
+            .ok_or_else(|| invalid("Not an S-record"))?;
+        if bytes.is_empty() || bytes.len() != bytes[0] as usize + 1 {
+            return Err(invalid("Wrong record length"));
+        }
+        if bytes.iter().fold(0u8, |acc, &b| acc.wrapping_add(b)) != 0xFF {
+            return Err(invalid("Bad checksum"));
+        }
+
+        let addr_len = match kind {
+            '0' | '1' | '5' | '9' => 2,
+            '2' | '6' | '8' => 3,
+            '3' | '7' => 4,
+            _ => return Err(invalid("Unsupported record")),
+        };
+        if bytes.len() < addr_len + 2 {
+            return Err(invalid("Wrong record length"));
+        }
+
+        let addr = be_number(&bytes[1..1 + addr_len]);
+        let data = &bytes[1 + addr_len..bytes.len() - 1];
+        match kind {
+            '1' | '2' | '3' => image.push(addr, data),
+            '7' | '8' | '9' => image.entry = Some(addr),
+            _ => {}
+        }
+    }
+
+    Ok(image)
+}
+
+// Picks the format from the extension of path, anything unknown is a flat image starting at 0
+pub fn parse(path: &str, data: Vec<u8>) -> Result<Image, String> {
+    let extension = Path::new(path)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or("");
+    let text = || String::from_utf8_lossy(&data).into_owned();
+    let image = match extension {
+        "ihex" | "ihx" => parse_intel_hex(&text()),
+        "srec" | "s19" | "s28" | "s37" => parse_srecord(&text()),
+        "hex0" | "hex1" | "hex2" => {
+            assembler::assemble(&text(), extension != "hex0").map(|(bin, debug_data)| Image {
+                segments: vec![(0, bin)],
+                entry: None,
+                debug_data: Some(debug_data),
+            })
+        }
+        _ => {
+            return Ok(Image {
+                segments: vec![(0, data)],
+                ..Default::default()
+            })
+        }
+    };
+
+    image.map_err(|e| format!("{path}: {e}"))
+}
+
+// Path of the program file for path, which is either a program file or a base path that gets
+// each of PROGRAM_EXTENSIONS appended, along with the base path
+fn find_program(path: &str) -> Option<(String, String)> {
+    match path.rsplit_once('.') {
+        Some((base, extension))
+            if PROGRAM_EXTENSIONS.contains(&extension) && Path::new(path).exists() =>
+        {
+            Some((base.to_owned(), path.to_owned()))
+        }
+        _ => PROGRAM_EXTENSIONS
+            .iter()
+            .map(|extension| format!("{path}.{extension}"))
+            .find(|program_path| Path::new(program_path).exists())
+            .map(|program_path| (path.to_owned(), program_path)),
+    }
+}
+
+pub fn read(path: &str) -> Result<Image, String> {
+    let data = std::fs::read(path).map_err(|e| format!("Failed to read {path}: {e}"))?;
+    parse(path, data)
+}
+
+// Reads the program at path, see find_program(). Also returns the base path without the
+// extension, which the debug files are found with.
+pub fn load_program(path: &str) -> Result<(String, Image), String> {
+    let (base_path, program_path) =
+        find_program(path).ok_or_else(|| format!("{path}.bin does not exist"))?;
+    Ok((base_path, read(&program_path)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{b:02X}")).collect()
+    }
+
+    fn ihex(kind: u8, addr: u16, data: &[u8]) -> String {
+        let mut bytes = vec![data.len() as u8];
+        bytes.extend_from_slice(&addr.to_be_bytes());
+        bytes.push(kind);
+        bytes.extend_from_slice(data);
+        let sum = bytes.iter().fold(0u8, |acc, &b| acc.wrapping_add(b));
+        bytes.push(sum.wrapping_neg());
+        format!(":{}\n", hex(&bytes))
+    }
+
+    fn srec(kind: char, addr: &[u8], data: &[u8]) -> String {
+        let mut bytes = vec![(addr.len() + data.len() + 1) as u8];
+        bytes.extend_from_slice(addr);
+        bytes.extend_from_slice(data);
+        let sum = bytes.iter().fold(0u8, |acc, &b| acc.wrapping_add(b));
+        bytes.push(!sum);
+        format!("S{kind}{}\n", hex(&bytes))
+    }
+
+    #[test]
+    fn intel_hex() {
+        let text = ihex(0, 0x10, &[1, 2]) + &ihex(0, 0x12, &[3]) + &ihex(1, 0, &[]);
+        let image = parse_intel_hex(&text).unwrap();
+        assert_eq!(image.segments, [(0x10, vec![1, 2, 3])]);
+        assert_eq!(image.entry, None);
+
+        // Nothing after the end of file record is read
+        let text = text + &ihex(0, 0, &[4]);
+        assert_eq!(parse_intel_hex(&text).unwrap().segments.len(), 1);
+    }
+
+    #[test]
+    fn intel_hex_addresses() {
+        let text = ihex(4, 0, &[0x13, 0xED])
+            + &ihex(0, 0x27F0, &[1])
+            + &ihex(2, 0, &[0x10, 0x00])
+            + &ihex(0, 0x0001, &[2])
+            + &ihex(5, 0, &[0, 1, 0, 0x18]);
+        let image = parse_intel_hex(&text).unwrap();
+        assert_eq!(image.segments, [(0x13ED27F0, vec![1]), (0x10001, vec![2])]);
+        assert_eq!(image.entry, Some(0x10018));
+
+        let image = parse_intel_hex(&ihex(3, 0, &[0x10, 0x00, 0x00, 0x18])).unwrap();
+        assert_eq!(image.entry, Some(0x10018));
+    }
+
+    #[test]
+    fn intel_hex_errors() {
+        let error = |text: &str| parse_intel_hex(text).err().unwrap();
+        let record = ihex(0, 0, &[1, 2]);
+        assert_eq!(error(&record.replace("FB", "FC")), "Line 1: Bad checksum");
+        assert_eq!(error(":0300000001020"), "Line 1: Not an Intel HEX record");
+        assert_eq!(error(":030000000102FA"), "Line 1: Wrong record length");
+        assert_eq!(error(":00000001"), "Line 1: Wrong record length");
+        assert_eq!(error("0200000001021B"), "Line 1: Not an Intel HEX record");
+        assert_eq!(
+            error(&(ihex(0, 0, &[]) + &ihex(4, 0, &[1]))),
+            "Line 2: Unsupported record"
+        );
+        assert_eq!(error(&ihex(6, 0, &[])), "Line 1: Unsupported record");
+    }
+
+    #[test]
+    fn srecords() {
+        let text = srec('0', &[0, 0], b"hdr")
+            + &srec('1', &[0, 0x10], &[1, 2])
+            + &srec('2', &[0, 0, 0x12], &[3])
+            + &srec('3', &[0x13, 0xED, 0x27, 0xF0], &[4])
+            + &srec('5', &[0, 3], &[])
+            + &srec('7', &[0, 0, 0, 0x18], &[]);
+        let image = parse_srecord(&text).unwrap();
+        assert_eq!(
+            image.segments,
+            [(0x10, vec![1, 2, 3]), (0x13ED27F0, vec![4])]
+        );
+        assert_eq!(image.entry, Some(0x18));
+
+        assert_eq!(
+            parse_srecord(&srec('9', &[0, 0x30], &[])).unwrap().entry,
+            Some(0x30)
+        );
+        assert_eq!(
+            parse_srecord(&srec('8', &[1, 0, 0], &[])).unwrap().entry,
+            Some(0x10000)
+        );
+    }
+
+    #[test]
+    fn srecord_errors() {
+        let error = |text: &str| parse_srecord(text).err().unwrap();
+        let record = srec('1', &[0, 0], &[1]);
+        assert_eq!(error(&record.replace("FA", "FB")), "Line 1: Bad checksum");
+        assert_eq!(error("S1050000010"), "Line 1: Not an S-record");
+        assert_eq!(error("S10600000100F8"), "Line 1: Wrong record length");
+        assert_eq!(error("S101FE"), "Line 1: Wrong record length");
+        assert_eq!(
+            error(&srec('4', &[0, 0], &[])),
+            "Line 1: Unsupported record"
+        );
+        assert_eq!(error("X1040000FB"), "Line 1: Not an S-record");
+    }
+
+    #[test]
+    fn load_bounds() {
+        let image = Image {
+            segments: vec![(0, vec![1, 2]), (6, vec![3, 4])],
+            ..Default::default()
+        };
+        assert_eq!(image.end(), 8);
+        assert_eq!(image.flatten(), [1, 2, 0, 0, 0, 0, 3, 4]);
+
+        let mut mem = vec![0; 10];
+        image.load_into(&mut mem, 2).unwrap();
+        assert_eq!(mem, [0, 0, 1, 2, 0, 0, 0, 0, 3, 4]);
+        assert_eq!(
+            image.load_into(&mut mem, 3),
+            Err("Segment at 0x9 (0x2 bytes) is outside of memory".to_owned())
+        );
+        assert!(image.load_into(&mut mem, u64::MAX - 2).is_err());
+    }
+
+    #[test]
+    fn hash_matches_bin() {
+        let bin = vec![1, 2, 3];
+        let image = parse("p.bin", bin.clone()).unwrap();
+        assert_eq!(image.hash(), perf::hash_bytes(&bin));
+
+        let text = ihex(0, 0, &[1]) + &ihex(0, 2, &[3]);
+        let image = parse("p.ihex", text.into_bytes()).unwrap();
+        assert_eq!(image.hash(), perf::hash_bytes(&[1, 0, 3]));
+    }
+}
@@ -0,0 +1,26 @@
+pub fn read(mem: &[u8], offset: usize) -> i64 {
+    i64::from_be_bytes(mem[offset..offset + 8].try_into().unwrap())
+}
+
+pub fn write(mem: &mut [u8], offset: usize, data: &[u8; 8]) {
+    mem[offset..offset + 8].clone_from_slice(data);
+}
+
+/// # Safety
+/// offset + 8 must not exceed mem.len()
+#[inline(always)]
+pub unsafe fn read_unchecked(mem: &[u8], offset: usize) -> i64 {
+    i64::from_be_bytes(std::ptr::read_unaligned(
+        mem.as_ptr().add(offset) as *const [u8; 8]
+    ))
+}
+
+/// # Safety
+/// offset + 8 must not exceed mem.len()
+#[inline(always)]
+pub unsafe fn write_unchecked(mem: &mut [u8], offset: usize, val: i64) {
+    std::ptr::write_unaligned(
+        mem.as_mut_ptr().add(offset) as *mut [u8; 8],
+        i64::to_be_bytes(val),
+    );
+}
@@ -0,0 +1,211 @@
+use std::io::Write;
+
+use clap::Parser;
+
+use noontide_emu::{
+    coverage::{self, BranchKind, InstructionCoverage},
+    loader, pdb,
+};
+
+#[derive(Parser)]
+#[command(name = "noontide-cov")]
+#[command(author = "NyanCatTW1")]
+#[command(about = "Generate a coverage report from a .cov file", long_about = None)]
+struct Cli {
+    #[arg(help = "Path to the .cov file")]
+    cov_path: String,
+
+    #[arg(help = "Base path of the program and its hex* and lsq files, as given to noontide-emu")]
+    base_path: String,
+
+    #[arg(long)]
+    #[arg(help = "Generate the report even if the .cov file was recorded from a different .bin")]
+    force: bool,
+
+    #[arg(long)]
+    #[arg(help = "Only print the coverage summary, without the uncovered lines")]
+    summary: bool,
+
+    #[arg(long)]
+    #[arg(help = "Also list the conditional branches that were never taken or always taken")]
+    branches: bool,
+
+    #[arg(long)]
+    #[arg(help = "Also write the coverage in lcov format to this path")]
+    lcov: Option<String>,
+}
+
+fn percentage(hits: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        hits as f64 * 100.0 / total as f64
+    }
+}
+
+fn source_line(debug_data: &pdb::DebugData, program_end: u64, eip: u64) -> Option<usize> {
+    (eip < program_end).then(|| pdb::find_line(debug_data, eip))
+}
+
+fn write_lcov(
+    path: &str,
+    source_path: &str,
+    debug_data: &pdb::DebugData,
+    program_end: u64,
+    hits: &[u64],
+    branches: &[InstructionCoverage],
+) {
+    let mut out = std::io::BufWriter::new(std::fs::File::create(path).unwrap());
+
+    writeln!(out, "TN:").unwrap();
+    writeln!(out, "SF:{source_path}").unwrap();
+
+    // Every conditional branch is its own block with two branches, taken and not taken
+    let mut branches_hit = 0;
+    for ins in branches {
+        let line = source_line(debug_data, program_end, ins.eip).unwrap();
+        writeln!(out, "BRDA:{},{},0,{}", line + 1, ins.eip, ins.taken).unwrap();
+        writeln!(out, "BRDA:{},{},1,{}", line + 1, ins.eip, ins.not_taken).unwrap();
+        branches_hit += (ins.taken != 0) as u64 + (ins.not_taken != 0) as u64;
+    }
+    writeln!(out, "BRF:{}", 2 * branches.len()).unwrap();
+    writeln!(out, "BRH:{branches_hit}").unwrap();
+
+    let mut found = 0;
+    let mut hit = 0;
+    for (i, &line_hits) in hits.iter().enumerate() {
+        if !coverage::is_code_line(debug_data, i, program_end) {
+            continue;
+        }
+
+        found += 1;
+        if line_hits != 0 {
+            hit += 1;
+        }
+        writeln!(out, "DA:{},{line_hits}", i + 1).unwrap();
+    }
+    writeln!(out, "LF:{found}").unwrap();
+    writeln!(out, "LH:{hit}").unwrap();
+    writeln!(out, "end_of_record").unwrap();
+}
+
+fn print_branches(title: &str, debug_data: &pdb::DebugData, branches: &[&InstructionCoverage]) {
+    println!("{title} ({}):", branches.len());
+    for ins in branches {
+        let line = pdb::find_line(debug_data, ins.eip);
+        println!(
+            "{:>6}: {:#010x} ({} executions) {}",
+            line + 1,
+            ins.eip,
+            ins.executions(),
+            debug_data.offsets[line].1
+        );
+    }
+}
+
+fn main() {
+    let cli = Cli::parse();
+
+    let coverage_data = match coverage::CoverageData::read(&cli.cov_path) {
+        Ok(coverage_data) => coverage_data,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            std::process::exit(1);
+        }
+    };
+
+    let (base_path, program) = match loader::load_program(&cli.base_path) {
+        Ok(program) => program,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            std::process::exit(1);
+        }
+    };
+
+    // Instructions are decoded from memory as the program was loaded
+    let bin = program.flatten();
+    let bin_hash = program.hash();
+    if coverage_data.bin_hash != bin_hash {
+        let mismatch = format!(
+            "{} was recorded from a different binary ({:#018x}, expected {bin_hash:#018x})",
+            cli.cov_path, coverage_data.bin_hash
+        );
+        if !cli.force {
+            eprintln!("Error: {mismatch}");
+            std::process::exit(1);
+        }
+        eprintln!("Warning: {mismatch}, continuing because of --force");
+    }
+
+    // MSQ details are never hidden, so that line numbers match the source file
+    let (Some(source_path), Some(debug_data)) = (
+        pdb::find_debug_source(&base_path),
+        pdb::find_debug_data(&base_path, 100),
+    ) else {
+        eprintln!("Error: Missing hex0, hex1, hex2, or lsq file for {base_path}");
+        std::process::exit(1);
+    };
+
+    let program_end = bin.len() as u64;
+    let hits = coverage::hits_per_line(&debug_data, &coverage_data.instructions, program_end);
+    let mut found = 0;
+    let mut covered = 0;
+    for (i, &line_hits) in hits.iter().enumerate() {
+        if !coverage::is_code_line(&debug_data, i, program_end) {
+            continue;
+        }
+
+        found += 1;
+        if line_hits != 0 {
+            covered += 1;
+        } else if !cli.summary {
+            println!("{:>6}: {}", i + 1, debug_data.offsets[i].1);
+        }
+    }
+
+    // Only conditional branches in the debugged code are interesting, jumps are always taken
+    // and everything else can only go one way
+    let branches: Vec<InstructionCoverage> = coverage_data
+        .instructions
+        .iter()
+        .filter(|ins| {
+            source_line(&debug_data, program_end, ins.eip).is_some()
+                && coverage::branch_kind(&bin, ins.eip) == BranchKind::Conditional
+        })
+        .copied()
+        .collect();
+    let branches_hit: u64 = branches
+        .iter()
+        .map(|ins| (ins.taken != 0) as u64 + (ins.not_taken != 0) as u64)
+        .sum();
+
+    if cli.branches {
+        let never_taken: Vec<&InstructionCoverage> =
+            branches.iter().filter(|ins| ins.taken == 0).collect();
+        let always_taken: Vec<&InstructionCoverage> =
+            branches.iter().filter(|ins| ins.not_taken == 0).collect();
+        print_branches("Never taken", &debug_data, &never_taken);
+        print_branches("Always taken", &debug_data, &always_taken);
+    }
+
+    println!(
+        "{source_path}: {covered} of {found} lines covered ({:.2}%)",
+        percentage(covered, found)
+    );
+    println!(
+        "{source_path}: {branches_hit} of {} directions of executed branches covered ({:.2}%)",
+        2 * branches.len(),
+        percentage(branches_hit, 2 * branches.len() as u64)
+    );
+
+    if let Some(lcov_path) = cli.lcov {
+        write_lcov(
+            &lcov_path,
+            &source_path,
+            &debug_data,
+            program_end,
+            &hits,
+            &branches,
+        );
+    }
+}
@@ -0,0 +1,733 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::Sender,
+        Arc,
+    },
+};
+
+use noontide_emu::{mem, pdb, snapshot};
+
+use crate::{
+    cpu::{Breakpoint, CpuCommand, CpuState, CPU_CONTROL_START},
+    expr::Expr,
+    serial::Transfer,
+};
+
+const HELP: &str = "\
+break <loc> [if <cond>] [after <n> hits]
+                         Pause a CPU before executing the instruction at loc, only if cond
+                         is nonzero and only from the nth time on
+log <loc> <expr> [if <cond>] [after <n> hits]
+                         Like break, but print the value of expr and keep running
+watch <loc>              Pause a CPU after it changes the 8-byte word at loc
+delete [n]               Delete breakpoint or watchpoint n, or all of them
+info breakpoints         List breakpoints and watchpoints
+cpu [n]                  Select CPU n for step, or show the selected CPU. A CPU that pauses
+                         gets selected.
+step [n]                 Run n instructions (default 1) on the selected CPU, then pause it
+continue                 Resume all CPUs
+pause                    Pause all CPUs
+x[/N[x|d|c]] <loc>       Examine N 8-byte words at loc in hex, decimal, or as characters
+set <loc> <value>        Write an 8-byte word, once no CPU is running
+display[/x|d|c|s] <loc>  Show the 8-byte word at loc in the Variables window in hex, decimal,
+                         as a character, or as a pointer to a NUL-terminated string
+undisplay [n]            Stop showing variable n, or all of them
+print <loc>              Show the address of a label or number and the word there
+dump <file> <loc> <len>  Write len bytes of memory starting at loc into a file
+snapshot save <file>     Save memory and the EIPs of all CPUs once no CPU is running, which
+                         --snapshot can start from
+send <file> [rate]       Stream a file into serial input at rate bytes per second (default 1000)
+send stop                Stop sending the file
+quit                     Exit the emulator
+Locations are numbers (0x for hex) or hex2 labels, optionally followed by +offset or -offset.
+Expressions combine numbers, labels, $eip and [addr] for the word at addr with + - == != < <=
+> >= && || ! and parentheses.";
+
+// What the front-end has to do after a command ran
+pub enum Outcome {
+    Output(String),
+    // The CPU was resumed, and will report back with UIMessage::Paused
+    Resumed,
+    Quit,
+}
+
+enum StopKind {
+    Breakpoint(Breakpoint),
+    Watchpoint(u64),
+}
+
+struct Stop {
+    id: usize,
+    // The command that created it
+    description: String,
+    kind: StopKind,
+}
+
+struct Variable {
+    loc: String,
+    addr: u64,
+    format: char,
+}
+
+// Parses and runs debugger commands, shared by the TUI command line and --script
+pub struct Console {
+    cpu_senders: Vec<Sender<CpuCommand>>,
+    cpu_states: Vec<Arc<CpuState>>,
+    selected_cpu: usize,
+    labels: HashMap<String, u64>,
+    stops: Vec<Stop>,
+    next_stop_id: usize,
+    // Shown in the Variables window
+    variables: Vec<Variable>,
+    serial_sender: Sender<u8>,
+    transfer: Option<Transfer>,
+}
+
+pub fn parse_number(inp: &str) -> Result<i64, String> {
+    let (negative, digits) = match inp.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, inp),
+    };
+
+    let parsed = match digits.strip_prefix("0x").or(digits.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => digits.parse::<u64>(),
+    }
+    .map_err(|e| format!("Invalid number {inp}: {e}"))?;
+
+    Ok(if negative {
+        (parsed as i64).wrapping_neg()
+    } else {
+        parsed as i64
+    })
+}
+
+// Up to 64 bytes, stopping at a NUL
+fn string_at(mem: &[u8], addr: u64) -> Result<String, String> {
+    let start = usize::try_from(addr)
+        .ok()
+        .filter(|&start| start < mem.len())
+        .ok_or_else(|| format!("{addr:#x} is outside of memory"))?;
+    let bytes: Vec<u8> = mem[start..]
+        .iter()
+        .take(64)
+        .take_while(|&&c| c != 0)
+        .copied()
+        .collect();
+    Ok(format!("{:?}", String::from_utf8_lossy(&bytes)))
+}
+
+fn char_of(val: i64) -> char {
+    match u8::try_from(val) {
+        Ok(c) if c.is_ascii_graphic() || c == b' ' => c as char,
+        _ => '.',
+    }
+}
+
+impl Console {
+    pub fn new(
+        cpu_senders: Vec<Sender<CpuCommand>>,
+        cpu_states: Vec<Arc<CpuState>>,
+        serial_sender: Sender<u8>,
+        debug_data: &Option<pdb::DebugData>,
+    ) -> Self {
+        Console {
+            cpu_senders,
+            cpu_states,
+            selected_cpu: 0,
+            labels: debug_data
+                .as_ref()
+                .map(pdb::find_labels)
+                .unwrap_or_default(),
+            stops: Vec::new(),
+            next_stop_id: 1,
+            variables: Vec::new(),
+            serial_sender,
+            transfer: None,
+        }
+    }
+
+    pub fn parse_location(&self, inp: &str) -> Result<u64, String> {
+        // Labels can contain - and +
+        if let Some(&addr) = self.labels.get(inp) {
+            return Ok(addr);
+        }
+
+        let split = inp.rfind(['+', '-']).filter(|&i| i > 0);
+        let (base, offset) = match split {
+            Some(i) => (&inp[..i], parse_number(&inp[i..].replace('+', ""))?),
+            None => (inp, 0),
+        };
+
+        let base = match self.labels.get(base) {
+            Some(&addr) => addr,
+            None => parse_number(base)
+                .map_err(|_| format!("Unknown label or invalid number: {base}"))?
+                as u64,
+        };
+
+        Ok(base.wrapping_add(offset as u64))
+    }
+
+    fn parse_word_location(&self, inp: &str, mem: &[u8]) -> Result<u64, String> {
+        let addr = self.parse_location(inp)?;
+        if addr.checked_add(8).is_none_or(|end| end > mem.len() as u64) {
+            return Err(format!("{addr:#x} is outside of memory"));
+        }
+
+        Ok(addr)
+    }
+
+    pub fn selected_cpu(&self) -> usize {
+        self.selected_cpu
+    }
+
+    pub fn select_cpu(&mut self, cpu_id: usize) {
+        self.selected_cpu = cpu_id;
+    }
+
+    // Called regularly by the front-ends. Returns a message once the file has been sent.
+    pub fn pump_transfer(&mut self) -> Option<String> {
+        let transfer = self.transfer.as_mut()?;
+        if !transfer.pump(&self.serial_sender) {
+            return None;
+        }
+
+        let done = transfer.progress().replacen("Sending", "Sent", 1);
+        self.transfer = None;
+        Some(done)
+    }
+
+    pub fn transfer_progress(&self) -> Option<String> {
+        self.transfer.as_ref().map(Transfer::progress)
+    }
+
+    // Memory can only be changed or saved consistently while no CPU is running
+    fn check_paused(&self, mem: &[u8]) -> Result<(), String> {
+        for (cpu_id, state) in self.cpu_states.iter().enumerate() {
+            let running = mem::read(mem, CPU_CONTROL_START + 16 * cpu_id) == 1;
+            if running && !state.paused.load(Ordering::Relaxed) {
+                return Err(format!("CPU {cpu_id} is running, pause it first"));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn broadcast(&self, command: CpuCommand) {
+        for cpu_sender in &self.cpu_senders {
+            cpu_sender.send(command.clone()).unwrap();
+        }
+    }
+
+    fn send_stops(&self) {
+        let mut breakpoints = Vec::new();
+        let mut watchpoints = Vec::new();
+        for stop in &self.stops {
+            match &stop.kind {
+                StopKind::Breakpoint(breakpoint) => breakpoints.push(breakpoint.clone()),
+                StopKind::Watchpoint(addr) => watchpoints.push(*addr),
+            }
+        }
+
+        self.broadcast(CpuCommand::SetBreakpoints(breakpoints));
+        self.broadcast(CpuCommand::SetWatchpoints(watchpoints));
+    }
+
+    fn parse_expr(&self, inp: &str) -> Result<Expr, String> {
+        Expr::parse(inp, &|label| self.parse_location(label))
+    }
+
+    fn add_stop(&mut self, description: String, kind: StopKind) -> usize {
+        let id = self.next_stop_id;
+        self.next_stop_id += 1;
+        self.stops.push(Stop {
+            id,
+            description,
+            kind,
+        });
+        self.send_stops();
+        id
+    }
+
+    // Parses <loc> [<expr>] [if <cond>] [after <n> hits], with the expression only being
+    // there for logging breakpoints
+    fn parse_breakpoint(&self, args: &[&str], log: bool) -> Result<Breakpoint, String> {
+        let Some((loc, args)) = args.split_first() else {
+            return Err("Missing location".to_owned());
+        };
+
+        let mut clauses: Vec<(&str, Vec<&str>)> = vec![("", Vec::new())];
+        for &arg in args {
+            if arg == "if" || arg == "after" {
+                if clauses.iter().any(|(keyword, _)| *keyword == arg) {
+                    return Err(format!("Duplicate {arg}"));
+                }
+                clauses.push((arg, Vec::new()));
+            } else {
+                clauses.last_mut().unwrap().1.push(arg);
+            }
+        }
+
+        let mut breakpoint = Breakpoint {
+            id: self.next_stop_id,
+            addr: self.parse_location(loc)?,
+            condition: None,
+            after_hits: 0,
+            log: None,
+            hits: Arc::new(AtomicU64::new(0)),
+        };
+        for (keyword, tokens) in clauses {
+            let text = tokens.join(" ");
+            match (keyword, tokens.as_slice()) {
+                ("", []) if !log => {}
+                ("", _) if !log => return Err(format!("Unexpected {text}")),
+                ("", _) => breakpoint.log = Some((text.clone(), self.parse_expr(&text)?)),
+                ("if", _) => breakpoint.condition = Some(self.parse_expr(&text)?),
+                ("after", [n] | [n, "hits"]) => breakpoint.after_hits = parse_number(n)? as u64,
+                _ => return Err("Usage: after <n> hits".to_owned()),
+            }
+        }
+
+        Ok(breakpoint)
+    }
+
+    fn examine(&self, format: &str, args: &[&str], mem: &[u8]) -> Result<String, String> {
+        // N, then an optional format letter, with an optional g for 8-byte words anywhere after N
+        let format = format.replace('g', "");
+        let (count, letter) = match format.chars().last() {
+            Some(letter @ ('x' | 'd' | 'c')) => (&format[..format.len() - 1], letter),
+            _ => (format.as_str(), 'x'),
+        };
+        let count = if count.is_empty() {
+            1
+        } else {
+            parse_number(count)? as u64
+        };
+
+        let [loc] = args else {
+            return Err("Usage: x[/N[x|d|c]] <loc>".to_owned());
+        };
+        let start = self.parse_word_location(loc, mem)?;
+        if start
+            .checked_add(8 * count)
+            .is_none_or(|end| end > mem.len() as u64)
+        {
+            return Err("Range is outside of memory".to_owned());
+        }
+
+        let mut lines = Vec::new();
+        for row in (0..count).step_by(4) {
+            let addr = start + 8 * row;
+            let mut line = format!("{addr:#010x}:");
+            for i in row..std::cmp::min(row + 4, count) {
+                let val = mem::read(mem, (start + 8 * i) as usize);
+                line.push_str(&match letter {
+                    'd' => format!(" {val:>20}"),
+                    'c' => format!(" {}", char_of(val)),
+                    _ => format!(" {val:#018x}"),
+                });
+            }
+            lines.push(line);
+        }
+
+        Ok(lines.join("\n"))
+    }
+
+    pub fn has_variables(&self) -> bool {
+        !self.variables.is_empty()
+    }
+
+    // One line per variable, read from the current memory
+    pub fn render_variables(&self, mem: &[u8]) -> String {
+        if self.variables.is_empty() {
+            return "No variables, add some with display <loc>".to_owned();
+        }
+
+        let mut lines = Vec::new();
+        for (i, variable) in self.variables.iter().enumerate() {
+            let val = mem::read(mem, variable.addr as usize);
+            let shown = match variable.format {
+                'd' => val.to_string(),
+                'c' => format!("'{}'", char_of(val)),
+                's' => match string_at(mem, val as u64) {
+                    Ok(string) => format!("{val:#x} {string}"),
+                    Err(e) => format!("{val:#x} <{e}>"),
+                },
+                _ => format!("{val:#x}"),
+            };
+            lines.push(format!(
+                "{}: {} ({:#x}) = {shown}",
+                i + 1,
+                variable.loc,
+                variable.addr
+            ));
+        }
+
+        lines.join("\n")
+    }
+
+    fn display(&mut self, format: &str, args: &[&str], mem: &[u8]) -> Result<String, String> {
+        let format = match format {
+            "" => 'x',
+            "x" | "d" | "c" | "s" => format.chars().next().unwrap(),
+            _ => return Err(format!("Invalid format {format}")),
+        };
+
+        match args {
+            [] => Ok(self.render_variables(mem)),
+            [loc] => {
+                let addr = self.parse_word_location(loc, mem)?;
+                self.variables.push(Variable {
+                    loc: loc.to_string(),
+                    addr,
+                    format,
+                });
+                Ok(format!("Variable {} at {addr:#x}", self.variables.len()))
+            }
+            _ => Err("Usage: display[/x|d|c|s] <loc>".to_owned()),
+        }
+    }
+
+    fn send(&mut self, path: &str, rate: i64) -> Result<String, String> {
+        if rate <= 0 {
+            return Err("The rate must be positive".to_owned());
+        }
+        if self.transfer.is_some() {
+            return Err("Already sending a file, see send stop".to_owned());
+        }
+
+        let transfer = Transfer::open(path, rate as u64)?;
+        let output = transfer.progress();
+        self.transfer = Some(transfer);
+        Ok(output)
+    }
+
+    pub fn execute(&mut self, line: &str, mem: &mut [u8]) -> Result<Outcome, String> {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        let Some((&command, args)) = tokens.split_first() else {
+            return Ok(Outcome::Output(String::new()));
+        };
+
+        let output = match (command, args) {
+            ("help", _) => HELP.to_owned(),
+            ("break" | "b" | "log", _) => {
+                let breakpoint = self.parse_breakpoint(args, command == "log")?;
+                let addr = breakpoint.addr;
+                let id = self.add_stop(tokens.join(" "), StopKind::Breakpoint(breakpoint));
+                format!("Breakpoint {id} at {addr:#x}")
+            }
+            ("watch", [loc]) => {
+                let addr = self.parse_word_location(loc, mem)?;
+                let id = self.add_stop(tokens.join(" "), StopKind::Watchpoint(addr));
+                format!("Watchpoint {id} at {addr:#x}")
+            }
+            ("delete", []) => {
+                self.stops.clear();
+                self.send_stops();
+                "Deleted all breakpoints and watchpoints".to_owned()
+            }
+            ("delete", [n]) => {
+                let n = parse_number(n)? as usize;
+                let Some(i) = self.stops.iter().position(|stop| stop.id == n) else {
+                    return Err(format!("No breakpoint or watchpoint {n}"));
+                };
+                self.stops.remove(i);
+                self.send_stops();
+                format!("Deleted {n}")
+            }
+            ("info", ["breakpoints" | "break" | "b"]) => {
+                let lines: Vec<String> = self
+                    .stops
+                    .iter()
+                    .map(|stop| match &stop.kind {
+                        StopKind::Breakpoint(breakpoint) => format!(
+                            "{}: {} (at {:#x}, hit {} times)",
+                            stop.id,
+                            stop.description,
+                            breakpoint.addr,
+                            breakpoint.hits.load(Ordering::Relaxed)
+                        ),
+                        StopKind::Watchpoint(addr) => {
+                            format!("{}: {} (at {addr:#x})", stop.id, stop.description)
+                        }
+                    })
+                    .collect();
+                if lines.is_empty() {
+                    "No breakpoints or watchpoints".to_owned()
+                } else {
+                    lines.join("\n")
+                }
+            }
+            ("cpu", []) => format!("CPU {} is selected", self.selected_cpu),
+            ("cpu", [n]) => {
+                let n = parse_number(n)? as usize;
+                if n >= self.cpu_states.len() {
+                    return Err(format!("No CPU {n}"));
+                }
+                self.selected_cpu = n;
+                format!("Selected CPU {n}")
+            }
+            ("step" | "s", []) => {
+                self.cpu_senders[self.selected_cpu]
+                    .send(CpuCommand::Step(1))
+                    .unwrap();
+                return Ok(Outcome::Resumed);
+            }
+            ("step" | "s", [n]) => {
+                let n = parse_number(n)?;
+                if n <= 0 {
+                    return Err("The step count must be positive".to_owned());
+                }
+                self.cpu_senders[self.selected_cpu]
+                    .send(CpuCommand::Step(n as u64))
+                    .unwrap();
+                return Ok(Outcome::Resumed);
+            }
+            ("continue" | "c", []) => {
+                self.broadcast(CpuCommand::Continue);
+                return Ok(Outcome::Resumed);
+            }
+            ("pause", []) => {
+                self.broadcast(CpuCommand::Pause);
+                "Pausing".to_owned()
+            }
+            ("x", _) => self.examine("", args, mem)?,
+            (command, _) if command.starts_with("x/") => self.examine(&command[2..], args, mem)?,
+            ("set", [loc, value]) => {
+                self.check_paused(mem)?;
+                let addr = self.parse_word_location(loc, mem)?;
+                let value = parse_number(value)?;
+                mem::write(mem, addr as usize, &value.to_be_bytes());
+                self.broadcast(CpuCommand::MemoryWritten(addr));
+                format!("[{addr:#x}] = {value:#x} ({value})")
+            }
+            ("display", _) => self.display("", args, mem)?,
+            (command, _) if command.starts_with("display/") => {
+                self.display(&command[8..], args, mem)?
+            }
+            ("undisplay", []) => {
+                self.variables.clear();
+                "Deleted all variables".to_owned()
+            }
+            ("undisplay", [n]) => {
+                let n = parse_number(n)? as usize;
+                if n == 0 || n > self.variables.len() {
+                    return Err(format!("No variable {n}"));
+                }
+                self.variables.remove(n - 1);
+                format!("Deleted variable {n}")
+            }
+            ("print" | "p", [loc]) => {
+                let addr = self.parse_word_location(loc, mem)?;
+                let value = mem::read(mem, addr as usize);
+                format!("{loc} = {addr:#x}: {value:#x} ({value})")
+            }
+            ("dump", [path, loc, len]) => {
+                let start = self.parse_location(loc)?;
+                let len = parse_number(len)? as u64;
+                if start
+                    .checked_add(len)
+                    .is_none_or(|end| end > mem.len() as u64)
+                {
+                    return Err("Range is outside of memory".to_owned());
+                }
+
+                std::fs::write(path, &mem[start as usize..(start + len) as usize])
+                    .map_err(|e| format!("Failed to write {path}: {e}"))?;
+                format!("Wrote {len} bytes to {path}")
+            }
+            ("snapshot", ["save", path]) => {
+                self.check_paused(mem)?;
+                let eips: Vec<u64> = self
+                    .cpu_states
+                    .iter()
+                    .map(|state| state.eip.load(Ordering::Relaxed))
+                    .collect();
+                snapshot::write(path, mem, &eips)?;
+                format!("Saved snapshot to {path}")
+            }
+            ("send", ["stop"]) => match self.transfer.take() {
+                Some(transfer) => format!("Stopped. {}", transfer.progress()),
+                None => return Err("Not sending anything".to_owned()),
+            },
+            ("send", [path]) => self.send(path, 1000)?,
+            ("send", [path, rate]) => self.send(path, parse_number(rate)?)?,
+            ("quit" | "q", []) => return Ok(Outcome::Quit),
+            _ => return Err(format!("Invalid command: {line} (see help)")),
+        };
+
+        Ok(Outcome::Output(output))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn console() -> Console {
+        let (serial_sender, _) = std::sync::mpsc::channel();
+        let mut console = Console::new(Vec::new(), Vec::new(), serial_sender, &None);
+        console.labels.insert("ptr".to_owned(), 0x18);
+        console
+    }
+
+    #[test]
+    fn variable_formats() {
+        let mut mem = vec![0; 0x40];
+        mem::write(&mut mem, 0x10, &(-2i64).to_be_bytes());
+        mem::write(&mut mem, 0x18, &0x20i64.to_be_bytes());
+        mem[0x20..0x23].copy_from_slice(b"hi\n");
+
+        let mut console = console();
+        assert_eq!(
+            console.render_variables(&mem),
+            "No variables, add some with display <loc>"
+        );
+        for (format, loc) in [("", "0x10"), ("d", "0x10"), ("c", "0x28"), ("s", "ptr")] {
+            console.display(format, &[loc], &mem).unwrap();
+        }
+        mem[0x2f] = b'A';
+        assert_eq!(
+            console.render_variables(&mem),
+            "1: 0x10 (0x10) = 0xfffffffffffffffe\n\
+             2: 0x10 (0x10) = -2\n\
+             3: 0x28 (0x28) = 'A'\n\
+             4: ptr (0x18) = 0x20 \"hi\\n\""
+        );
+
+        // Strings follow the pointer as it changes
+        mem::write(&mut mem, 0x18, &0x1000i64.to_be_bytes());
+        assert!(console
+            .render_variables(&mem)
+            .ends_with("4: ptr (0x18) = 0x1000 <0x1000 is outside of memory>"));
+    }
+
+    #[test]
+    fn invalid_variables() {
+        let mem = vec![0; 0x40];
+        let mut console = console();
+        assert!(console.display("q", &["0x10"], &mem).is_err());
+        assert!(console.display("x", &["0x39"], &mem).is_err());
+        assert!(console.display("x", &["0x10", "0x18"], &mem).is_err());
+        assert!(!console.has_variables());
+    }
+
+    #[test]
+    fn numbers_and_locations() {
+        assert_eq!(parse_number("42"), Ok(42));
+        assert_eq!(parse_number("0x1F"), Ok(0x1f));
+        assert_eq!(parse_number("-0X10"), Ok(-0x10));
+        assert_eq!(parse_number("0xffffffffffffffff"), Ok(-1));
+        assert!(parse_number("").is_err());
+        assert!(parse_number("0x").is_err());
+        assert!(parse_number("12a").is_err());
+
+        let mut console = console();
+        console.labels.insert("end-of-code".to_owned(), 0x100);
+        assert_eq!(console.parse_location("ptr"), Ok(0x18));
+        assert_eq!(console.parse_location("ptr+8"), Ok(0x20));
+        assert_eq!(console.parse_location("ptr-0x8"), Ok(0x10));
+        assert_eq!(console.parse_location("0x10+0x10"), Ok(0x20));
+        assert_eq!(console.parse_location("end-of-code"), Ok(0x100));
+        assert_eq!(console.parse_location("end-of-code+8"), Ok(0x108));
+        assert_eq!(console.parse_location("-8"), Ok(u64::MAX - 7));
+        assert_eq!(
+            console.parse_location("nowhere+8"),
+            Err("Unknown label or invalid number: nowhere".to_owned())
+        );
+        assert!(console.parse_location("ptr+x").is_err());
+    }
+
+    // Two CPUs, running, with memory up to their control words
+    fn running_console() -> (Console, Vec<std::sync::mpsc::Receiver<CpuCommand>>, Vec<u8>) {
+        let (serial_sender, _) = std::sync::mpsc::channel();
+        let (cpu_senders, cpu_receivers) = (0..2).map(|_| std::sync::mpsc::channel()).unzip();
+        let cpu_states: Vec<Arc<CpuState>> = (0..2).map(|_| Arc::default()).collect();
+        cpu_states[1].eip.store(0x30, Ordering::Relaxed);
+        let console = Console::new(cpu_senders, cpu_states, serial_sender, &None);
+
+        let mut mem = vec![0; CPU_CONTROL_START + 32];
+        for cpu_id in 0..2 {
+            mem::write(
+                &mut mem,
+                CPU_CONTROL_START + 16 * cpu_id,
+                &1i64.to_be_bytes(),
+            );
+        }
+        (console, cpu_receivers, mem)
+    }
+
+    fn output(outcome: Result<Outcome, String>) -> Result<String, String> {
+        match outcome? {
+            Outcome::Output(output) => Ok(output),
+            _ => panic!("Expected output"),
+        }
+    }
+
+    #[test]
+    fn set_waits_for_paused_cpus() {
+        let (mut console, cpu_receivers, mut mem) = running_console();
+        assert_eq!(
+            output(console.execute("set 0x10 5", &mut mem)),
+            Err("CPU 0 is running, pause it first".to_owned())
+        );
+
+        // A stopped CPU counts as paused
+        console.cpu_states[0].paused.store(true, Ordering::Relaxed);
+        mem::write(&mut mem, CPU_CONTROL_START + 16, &4i64.to_be_bytes());
+        assert_eq!(
+            output(console.execute("set 0x10 -0x2", &mut mem)),
+            Ok("[0x10] = 0xfffffffffffffffe (-2)".to_owned())
+        );
+        assert_eq!(mem::read(&mem, 0x10), -2);
+        for receiver in &cpu_receivers {
+            assert!(matches!(
+                receiver.try_recv(),
+                Ok(CpuCommand::MemoryWritten(0x10))
+            ));
+        }
+
+        assert!(console.execute("set 0x10", &mut mem).is_err());
+        assert!(console.execute("set 0x10 x", &mut mem).is_err());
+        let end = format!("set {:#x} 1", mem.len() - 4);
+        assert!(console.execute(&end, &mut mem).is_err());
+        assert!(cpu_receivers[0].try_recv().is_err());
+    }
+
+    #[test]
+    fn snapshot_save() {
+        let path = std::env::temp_dir().join(format!("console-{}.snap", std::process::id()));
+        let path = path.to_str().unwrap();
+        let command = format!("snapshot save {path}");
+
+        let (mut console, _cpu_receivers, mut mem) = running_console();
+        mem::write(&mut mem, 0x10, &7i64.to_be_bytes());
+        assert_eq!(
+            output(console.execute(&command, &mut mem)),
+            Err("CPU 0 is running, pause it first".to_owned())
+        );
+        console.cpu_states[0].paused.store(true, Ordering::Relaxed);
+        assert_eq!(
+            output(console.execute(&command, &mut mem)),
+            Err("CPU 1 is running, pause it first".to_owned())
+        );
+
+        console.cpu_states[1].paused.store(true, Ordering::Relaxed);
+        assert_eq!(
+            output(console.execute(&command, &mut mem)),
+            Ok(format!("Saved snapshot to {path}"))
+        );
+        let mut loaded = vec![0; mem.len()];
+        assert_eq!(snapshot::read(path, &mut loaded), Ok(vec![0, 0x30]));
+        assert!(loaded == mem);
+        std::fs::remove_file(path).unwrap();
+
+        assert!(console.execute("snapshot save", &mut mem).is_err());
+        assert!(console
+            .execute("snapshot save /nonexistent/console.snap", &mut mem)
+            .is_err());
+    }
+}
@@ -1,10 +1,316 @@
-use std::sync::{mpsc::Sender, Arc, Barrier};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{Receiver, Sender},
+        Arc, Barrier, Mutex,
+    },
+};
 
 use bus::BusReader;
+use noontide_emu::{
+    block::BlockCache,
+    coverage::CoverageRecorder,
+    exec::{self, Event},
+    smc::SmcTracker,
+    trace::{TraceRecord, TraceWriter},
+};
 
-use crate::msg::UIMessage;
+use crate::{expr::Expr, msg::UIMessage};
 
-const CPU_CONTROL_START: usize = 0x13EE0000;
+pub const CPU_CONTROL_START: usize = 0x13EE0000;
+const SERIAL_OUT: u64 = 0x13ED27F0;
+
+pub struct CpuOptions {
+    pub initial_eip: u64,
+    // Maximum number of instructions to run between two device synchronizations
+    pub cycle_length: u64,
+    // Record the EIP once every this many instructions
+    pub sample_interval: Option<u64>,
+    pub debug_enabled: Arc<AtomicBool>,
+    pub trace: Option<TraceWriter>,
+    // Execute code through a cache of decoded basic blocks
+    pub blocks: bool,
+    // Also execute common instruction sequences in those blocks natively
+    pub idioms: bool,
+    // Report self-modification of the code loaded from the .bin
+    pub smc: Option<SmcTracker>,
+    pub coverage: Option<CoverageRecorder>,
+    pub commands: Receiver<CpuCommand>,
+    // Wait for a Continue or Step command before running anything
+    pub start_paused: bool,
+}
+
+// Commands the UI sends to a CPU, applied at the start of its next cycle
+#[derive(Clone)]
+pub enum CpuCommand {
+    Pause,
+    Continue,
+    // Run this many instructions, then pause again
+    Step(u64),
+    SetBreakpoints(Vec<Breakpoint>),
+    SetWatchpoints(Vec<u64>),
+    // The UI wrote the 8 bytes at this address, so anything cached about them is stale
+    MemoryWritten(u64),
+}
+
+#[derive(Clone)]
+pub struct Breakpoint {
+    pub id: usize,
+    pub addr: u64,
+    // Only counts as a hit when this evaluates to nonzero
+    pub condition: Option<Expr>,
+    // Ignore the hits before this one
+    pub after_hits: u64,
+    // Report the value of this expression, described by the string, instead of pausing
+    pub log: Option<(String, Expr)>,
+    // Shared with the UI so that it can show them
+    pub hits: Arc<AtomicU64>,
+}
+
+#[derive(Default)]
+struct Debugger {
+    paused: bool,
+    steps_left: Option<u64>,
+    // EIP -> breakpoints there
+    breakpoints: HashMap<u64, Vec<Breakpoint>>,
+    // Addresses of 8-byte words whose changes pause the CPU
+    watchpoints: Vec<u64>,
+    // Resuming at a breakpoint must not hit it again right away
+    resume_eip: Option<u64>,
+    // Report every instruction to the UI
+    log: bool,
+}
+
+impl Debugger {
+    // Whether instructions have to be checked one by one
+    fn has_stops(&self) -> bool {
+        !self.breakpoints.is_empty() || !self.watchpoints.is_empty()
+    }
+
+    // Counts the hits of the breakpoints at EIP and sends out their logs, returning why the
+    // CPU should pause if it should
+    fn check_breakpoints(
+        &self,
+        mem: &[u8],
+        cpu_id: usize,
+        eip: u64,
+        ui_sender: &Sender<UIMessage>,
+    ) -> Option<String> {
+        if self.resume_eip == Some(eip) {
+            return None;
+        }
+
+        let mut reason = None;
+        for breakpoint in self.breakpoints.get(&eip)? {
+            let id = breakpoint.id;
+            if let Some(condition) = &breakpoint.condition {
+                match condition.eval(mem, eip) {
+                    Ok(0) => continue,
+                    Ok(_) => {}
+                    Err(e) => {
+                        reason = Some(format!(
+                            "Breakpoint {id} at {eip:#x}: Condition failed: {e}"
+                        ));
+                        continue;
+                    }
+                }
+            }
+
+            let hits = breakpoint.hits.fetch_add(1, Ordering::Relaxed) + 1;
+            if hits < breakpoint.after_hits {
+                continue;
+            }
+
+            match &breakpoint.log {
+                Some((text, expr)) => {
+                    let out = match expr.eval(mem, eip) {
+                        Ok(val) => format!("{text} = {val:#x} ({val})"),
+                        Err(e) => format!("{text}: {e}"),
+                    };
+                    ui_sender.send(UIMessage::Log(cpu_id, eip, out)).unwrap();
+                }
+                None => reason = Some(format!("Breakpoint {id} at {eip:#x}, hit {hits} times")),
+            }
+        }
+
+        reason
+    }
+
+    fn pause(&mut self, cpu_id: usize, eip: u64, reason: String, ui_sender: &Sender<UIMessage>) {
+        self.paused = true;
+        self.steps_left = None;
+        ui_sender
+            .send(UIMessage::Paused(cpu_id, eip, reason))
+            .unwrap();
+    }
+}
+
+// State of a CPU that the UI polls instead of being sent messages
+#[derive(Default)]
+pub struct CpuState {
+    pub eip: AtomicU64,
+    pub paused: AtomicBool,
+    pub instructions: AtomicU64,
+    // EIP -> hits, filled in once the CPU exits
+    pub samples: Mutex<HashMap<u64, u64>>,
+    // Handed over once the CPU exits
+    pub coverage: Mutex<Option<CoverageRecorder>>,
+}
+
+// Executes instructions one by one, reporting every instruction to the UI if logging is on,
+// recording it into the trace and the coverage if there are any, checking it for
+// self-modification, and pausing at breakpoints and watchpoints. Writes are passed on to the
+// block cache, if there is one, so that it never runs stale code afterwards.
+#[allow(clippy::too_many_arguments)]
+fn run_slow(
+    mem: &mut [u8],
+    cpu_id: usize,
+    eip: &mut u64,
+    budget: u64,
+    ui_sender: &Sender<UIMessage>,
+    options: &mut CpuOptions,
+    debugger: &mut Debugger,
+    block_cache: &mut Option<BlockCache>,
+) -> (u64, Event) {
+    let mut executed = 0;
+    while executed < budget {
+        if !exec::eip_in_bounds(mem, *eip) {
+            return (executed, Event::EipOutOfBounds);
+        }
+
+        if let Some(reason) = debugger.check_breakpoints(mem, cpu_id, *eip, ui_sender) {
+            debugger.pause(cpu_id, *eip, reason, ui_sender);
+            return (executed, Event::Budget);
+        }
+        debugger.resume_eip = None;
+
+        let ins = exec::step(mem, *eip);
+        executed += 1;
+
+        if debugger.log {
+            ui_sender
+                .send(UIMessage::Debug(
+                    cpu_id,
+                    ins.eip,
+                    format!(
+                        "{:#X} {:#X}({:#X}) {:#X}({:#X}) {:#X}",
+                        ins.eip, ins.a_addr, ins.a_before, ins.b_addr, ins.b_val, ins.c_addr
+                    ),
+                ))
+                .unwrap();
+
+            if ins.a_addr == SERIAL_OUT {
+                ui_sender
+                    .send(UIMessage::Debug(
+                        cpu_id,
+                        ins.eip,
+                        format!("Serial write: {:#x} @ {:#x}", ins.a_after() - 1, ins.eip),
+                    ))
+                    .unwrap();
+            }
+        }
+
+        if let Some(trace) = &mut options.trace {
+            trace.record(&TraceRecord {
+                cpu: cpu_id as u16,
+                eip: ins.eip,
+                a_addr: ins.a_addr,
+                a_before: ins.a_before,
+                b_val: ins.b_val,
+                branch_taken: ins.branch_taken(),
+            });
+        }
+
+        if let Some(coverage) = &mut options.coverage {
+            coverage.record(ins.eip, ins.branch_taken());
+        }
+
+        if let Some(smc) = &mut options.smc {
+            smc.check(&ins, |event| {
+                ui_sender.send(UIMessage::Smc(cpu_id, event)).unwrap();
+            });
+        }
+
+        *eip = ins.next_eip();
+
+        if ins.b_val != 0 {
+            if let Some(block_cache) = block_cache {
+                block_cache.memory_written(mem, ins.a_addr);
+            }
+
+            let watched = debugger
+                .watchpoints
+                .iter()
+                .find(|&&addr| ins.a_addr < addr + 8 && addr < ins.a_addr + 8);
+            if let Some(&addr) = watched {
+                let reason = format!(
+                    "Watchpoint at {addr:#x}: written by {:#x}, {:#x} -> {:#x}",
+                    ins.eip,
+                    ins.a_before,
+                    ins.a_after()
+                );
+                debugger.pause(cpu_id, *eip, reason, ui_sender);
+                return (executed, Event::Budget);
+            }
+        }
+
+        if ins.accesses_device() {
+            return (executed, Event::DeviceAccess);
+        }
+    }
+
+    (executed, Event::Budget)
+}
+
+// Applies the commands sent by the console since the last time
+fn handle_commands(
+    commands: &Receiver<CpuCommand>,
+    mem: &[u8],
+    cpu_id: usize,
+    eip: u64,
+    debugger: &mut Debugger,
+    block_cache: &mut Option<BlockCache>,
+    ui_sender: &Sender<UIMessage>,
+) {
+    while let Ok(command) = commands.try_recv() {
+        match command {
+            CpuCommand::Pause => {
+                if !debugger.paused {
+                    debugger.pause(cpu_id, eip, "Paused".to_owned(), ui_sender);
+                }
+            }
+            CpuCommand::Continue => {
+                debugger.paused = false;
+                debugger.steps_left = None;
+                debugger.resume_eip = Some(eip);
+            }
+            CpuCommand::Step(count) => {
+                debugger.paused = false;
+                debugger.steps_left = Some(count);
+                debugger.resume_eip = Some(eip);
+            }
+            CpuCommand::SetBreakpoints(breakpoints) => {
+                debugger.breakpoints.clear();
+                for breakpoint in breakpoints {
+                    debugger
+                        .breakpoints
+                        .entry(breakpoint.addr)
+                        .or_default()
+                        .push(breakpoint);
+                }
+            }
+            CpuCommand::SetWatchpoints(watchpoints) => {
+                debugger.watchpoints = watchpoints;
+            }
+            CpuCommand::MemoryWritten(addr) => {
+                if let Some(block_cache) = block_cache {
+                    block_cache.memory_written(mem, addr);
+                }
+            }
+        }
+    }
+}
 
 pub fn cpu_loop(
     mem: &mut [u8],
@@ -12,112 +318,173 @@ pub fn cpu_loop(
     cpu_barrier: Arc<Barrier>,
     ui_sender: Sender<UIMessage>,
     mut term_rx: BusReader<usize>,
-    cycle_length: u32,
+    mut options: CpuOptions,
+    state: Arc<CpuState>,
 ) {
     let cpu_control_status = CPU_CONTROL_START + 16 * cpu_id;
     let cpu_control_eip = cpu_control_status + 8;
 
     if cpu_id == 0 {
         // Enable CPU 0 by default
-        crate::mem::write(mem, cpu_control_status, &u64::to_be_bytes(1));
+        noontide_emu::mem::write(mem, cpu_control_status, &u64::to_be_bytes(1));
     }
 
-    let mut eip: u64 = 0;
-    loop {
+    let mut eip: u64 = options.initial_eip;
+    let mut instructions: u64 = 0;
+    let mut samples: HashMap<u64, u64> = HashMap::new();
+    let mut until_sample = options.sample_interval.unwrap_or(u64::MAX);
+    let mut block_cache = options.blocks.then(|| BlockCache::new(mem, options.idioms));
+    let mut debugger = Debugger {
+        paused: options.start_paused,
+        ..Default::default()
+    };
+    'cpu: loop {
         // CPU cycle start
         cpu_barrier.wait();
 
         if let Ok(_val) = term_rx.try_recv() {
             cpu_barrier.wait();
             // eprintln!("CPU {} exited", cpu_id);
-            return;
+            break 'cpu;
         }
 
         // CPU is not running
-        if crate::mem::read(mem, cpu_control_status) != 1 {
-            if crate::mem::read(mem, cpu_control_status) == 2 {
-                crate::mem::write(mem, cpu_control_status, &u64::to_be_bytes(4));
+        if noontide_emu::mem::read(mem, cpu_control_status) != 1 {
+            if noontide_emu::mem::read(mem, cpu_control_status) == 2 {
+                noontide_emu::mem::write(mem, cpu_control_status, &u64::to_be_bytes(4));
                 ui_sender.send(UIMessage::CPUStopped(cpu_id)).unwrap();
             }
 
             // CPU cycle end
             cpu_barrier.wait();
 
-            while crate::mem::read(mem, cpu_control_status) != 1 {
+            while noontide_emu::mem::read(mem, cpu_control_status) != 1 {
                 cpu_barrier.wait();
 
                 if let Ok(_val) = term_rx.try_recv() {
                     cpu_barrier.wait();
                     // eprintln!("CPU {} exited", cpu_id);
-                    return;
+                    break 'cpu;
                 }
 
+                // A stopped CPU can still be paused, or be told to continue once started
+                handle_commands(
+                    &options.commands,
+                    mem,
+                    cpu_id,
+                    eip,
+                    &mut debugger,
+                    &mut block_cache,
+                    &ui_sender,
+                );
+                state.paused.store(debugger.paused, Ordering::Relaxed);
+
                 cpu_barrier.wait();
             }
 
-            eip = crate::mem::read(mem, cpu_control_eip) as u64;
+            eip = noontide_emu::mem::read(mem, cpu_control_eip) as u64;
             ui_sender.send(UIMessage::CPUStarted(cpu_id)).unwrap();
 
             // CPU cycle start
             cpu_barrier.wait();
         }
 
-        for _i in 0..cycle_length {
-            if (eip as usize) >= mem.len() {
-                if ui_sender.send(UIMessage::SetEIP(eip)).is_err() {
-                    break;
-                }
-                std::thread::sleep(std::time::Duration::from_millis(3600000));
-                panic!("EIP is outside of the memory region!");
-            }
-
-            let a_addr = crate::mem::read(mem, eip as usize);
-            let b_addr = crate::mem::read(mem, (eip + 8) as usize);
-            let c_addr = crate::mem::read(mem, (eip + 16) as usize);
+        handle_commands(
+            &options.commands,
+            mem,
+            cpu_id,
+            eip,
+            &mut debugger,
+            &mut block_cache,
+            &ui_sender,
+        );
 
-            let mut a_val = crate::mem::read(mem, a_addr as usize);
-            let b_val = crate::mem::read(mem, b_addr as usize);
+        // Run until the cycle budget runs out or a device is accessed, in which case the
+        // devices get to react right away. Single-instruction debugging runs one instruction
+        // per cycle so that the UI can follow along.
+        let debug = options.debug_enabled.load(Ordering::Relaxed);
+        debugger.log = debug;
+        let mut budget = if debugger.paused {
+            0
+        } else if debug {
+            1
+        } else {
+            options.cycle_length
+        };
+        if let Some(steps_left) = debugger.steps_left {
+            budget = std::cmp::min(budget, steps_left);
+        }
 
-            #[cfg(feature = "debugger")]
+        while budget != 0 {
+            let chunk = std::cmp::min(budget, until_sample);
+            let (executed, event) = if debug
+                || options.trace.is_some()
+                || options.smc.is_some()
+                || options.coverage.is_some()
+                || debugger.has_stops()
             {
-                ui_sender
-                    .send(UIMessage::Debug(
-                        eip,
-                        format!(
-                            "{eip:#X} {a_addr:#X}({a_val:#X}) {b_addr:#X}({b_val:#X}) {c_addr:#X}"
-                        ),
-                    ))
-                    .unwrap();
-            }
+                run_slow(
+                    mem,
+                    cpu_id,
+                    &mut eip,
+                    chunk,
+                    &ui_sender,
+                    &mut options,
+                    &mut debugger,
+                    &mut block_cache,
+                )
+            } else if let Some(block_cache) = &mut block_cache {
+                block_cache.run(mem, &mut eip, chunk)
+            } else {
+                exec::run(mem, &mut eip, chunk)
+            };
 
-            a_val = a_val.wrapping_sub(b_val);
-            crate::mem::write(mem, a_addr as usize, &i64::to_be_bytes(a_val));
+            budget -= executed;
+            instructions += executed;
+            if let Some(sample_interval) = options.sample_interval {
+                until_sample -= executed;
+                if until_sample == 0 {
+                    *samples.entry(eip).or_insert(0) += 1;
+                    until_sample = sample_interval;
+                }
+            }
 
-            #[cfg(feature = "debugger")]
-            {
-                if a_addr == 0x13ED27F0 {
-                    a_val -= 1;
-                    ui_sender
-                        .send(UIMessage::Debug(
-                            eip,
-                            format!("Serial write: {a_val:#x} @ {eip:#x}"),
-                        ))
-                        .unwrap();
+            if let Some(steps_left) = debugger.steps_left {
+                debugger.steps_left = Some(steps_left - executed);
+                if steps_left == executed {
+                    debugger.pause(cpu_id, eip, "Step finished".to_owned(), &ui_sender);
                 }
             }
 
-            if a_val <= 0 {
-                eip = c_addr as u64;
-            } else {
-                eip += 24;
+            match event {
+                Event::Budget if debugger.paused => break,
+                Event::Budget => {}
+                Event::DeviceAccess => break,
+                Event::EipOutOfBounds => {
+                    state.eip.store(eip, Ordering::Relaxed);
+                    std::thread::sleep(std::time::Duration::from_millis(3600000));
+                    panic!("EIP is outside of the memory region!");
+                }
             }
         }
 
-        crate::mem::write(mem, cpu_control_eip, &u64::to_be_bytes(eip));
+        noontide_emu::mem::write(mem, cpu_control_eip, &u64::to_be_bytes(eip));
+        state.eip.store(eip, Ordering::Relaxed);
+        state.instructions.store(instructions, Ordering::Relaxed);
+        state.paused.store(debugger.paused, Ordering::Relaxed);
+        if let Some(trace) = &mut options.trace {
+            trace.flush();
+        }
 
-        ui_sender.send(UIMessage::SetEIP(eip)).unwrap();
+        // Don't spin while waiting for the UI
+        if debugger.paused {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
 
         // CPU cycle end
         cpu_barrier.wait();
     }
+
+    *state.samples.lock().unwrap() = samples;
+    *state.coverage.lock().unwrap() = options.coverage.take();
 }
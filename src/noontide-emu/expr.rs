@@ -0,0 +1,324 @@
+use noontide_emu::mem;
+
+// Expressions over guest memory, used by conditional and logging breakpoints. Labels are
+// resolved when parsing, so that the CPU thread only has to deal with numbers.
+//   expr    := or
+//   or      := and ("||" and)*
+//   and     := compare ("&&" compare)*
+//   compare := sum (("==" | "!=" | "<" | "<=" | ">" | ">=") sum)?
+//   sum     := unary (("+" | "-") unary)*
+//   unary   := "-" unary | "!" unary | primary
+//   primary := number | label | "$eip" | "[" expr "]" | "(" expr ")"
+#[derive(Clone, Debug)]
+pub enum Expr {
+    Number(i64),
+    Eip,
+    // The 8-byte word at an address
+    Word(Box<Expr>),
+    Neg(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(Op, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Op {
+    Add,
+    Sub,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+enum Token {
+    Number(i64),
+    Name(String),
+    Op(&'static str),
+}
+
+const OPERATORS: [&str; 16] = [
+    "==", "!=", "<=", ">=", "&&", "||", "<", ">", "+", "-", "!", "[", "]", "(", ")", "=",
+];
+
+// Length of the number or name at the start of inp
+fn word_len(inp: &str) -> usize {
+    inp.find(|c: char| c.is_whitespace() || OPERATORS.iter().any(|op| op.starts_with(c)))
+        .unwrap_or(inp.len())
+}
+
+fn tokenize(
+    inp: &str,
+    resolve: &dyn Fn(&str) -> Result<u64, String>,
+) -> Result<Vec<Token>, String> {
+    let mut ret = Vec::new();
+    let mut rest = inp.trim_start();
+    while !rest.is_empty() {
+        if let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+            if *op == "=" {
+                return Err("Use == for comparisons".to_owned());
+            }
+            ret.push(Token::Op(op));
+            rest = &rest[op.len()..];
+        } else {
+            let mut len = word_len(rest);
+            let word = &rest[..len];
+            if word.starts_with(|c: char| c.is_ascii_digit()) {
+                ret.push(Token::Number(crate::console::parse_number(word)?));
+            } else {
+                // Labels can contain - and +, so take the longest name that resolves
+                let mut end = len;
+                while rest[end..].starts_with(['-', '+']) {
+                    end += 1 + word_len(&rest[end + 1..]);
+                    if resolve(&rest[..end]).is_ok() {
+                        len = end;
+                    }
+                }
+                let word = &rest[..len];
+                ret.push(Token::Name(word.to_owned()));
+            }
+            rest = &rest[len..];
+        }
+
+        rest = rest.trim_start();
+    }
+
+    Ok(ret)
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    resolve: &'a dyn Fn(&str) -> Result<u64, String>,
+}
+
+impl Parser<'_> {
+    fn eat(&mut self, op: &'static str) -> bool {
+        if self.tokens.get(self.pos) == Some(&Token::Op(op)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn binary(
+        &mut self,
+        ops: &[(&'static str, Op)],
+        next: fn(&mut Self) -> Result<Expr, String>,
+        chain: bool,
+    ) -> Result<Expr, String> {
+        let mut lhs = next(self)?;
+        while let Some(&(_, op)) = ops.iter().find(|(token, _)| self.eat(token)) {
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(next(self)?));
+            if !chain {
+                break;
+            }
+        }
+
+        Ok(lhs)
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        self.binary(&[("||", Op::Or)], Self::and, true)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        self.binary(&[("&&", Op::And)], Self::compare, true)
+    }
+
+    fn compare(&mut self) -> Result<Expr, String> {
+        self.binary(
+            &[
+                ("==", Op::Eq),
+                ("!=", Op::Ne),
+                ("<=", Op::Le),
+                (">=", Op::Ge),
+                ("<", Op::Lt),
+                (">", Op::Gt),
+            ],
+            Self::sum,
+            false,
+        )
+    }
+
+    fn sum(&mut self) -> Result<Expr, String> {
+        self.binary(&[("+", Op::Add), ("-", Op::Sub)], Self::unary, true)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        if self.eat("-") {
+            Ok(Expr::Neg(Box::new(self.unary()?)))
+        } else if self.eat("!") {
+            Ok(Expr::Not(Box::new(self.unary()?)))
+        } else {
+            self.primary()
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        if self.eat("[") {
+            let addr = self.or()?;
+            if !self.eat("]") {
+                return Err("Missing ]".to_owned());
+            }
+            return Ok(Expr::Word(Box::new(addr)));
+        }
+
+        if self.eat("(") {
+            let inner = self.or()?;
+            if !self.eat(")") {
+                return Err("Missing )".to_owned());
+            }
+            return Ok(inner);
+        }
+
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        match token {
+            Some(Token::Number(val)) => Ok(Expr::Number(val)),
+            Some(Token::Name(name)) if name == "$eip" => Ok(Expr::Eip),
+            Some(Token::Name(name)) => Ok(Expr::Number((self.resolve)(&name)? as i64)),
+            Some(Token::Op(op)) => Err(format!("Unexpected {op}")),
+            None => Err("Unexpected end of expression".to_owned()),
+        }
+    }
+}
+
+impl Expr {
+    // resolve turns a label into its address
+    pub fn parse(inp: &str, resolve: &dyn Fn(&str) -> Result<u64, String>) -> Result<Self, String> {
+        let mut parser = Parser {
+            tokens: tokenize(inp, resolve)?,
+            pos: 0,
+            resolve,
+        };
+        if parser.tokens.is_empty() {
+            return Err("Missing expression".to_owned());
+        }
+
+        let ret = parser.or()?;
+        match parser.tokens.get(parser.pos) {
+            None => Ok(ret),
+            Some(token) => Err(format!("Unexpected {token:?} in expression")),
+        }
+    }
+
+    // Arithmetic wraps like SUBLEQ does, comparisons give 1 or 0
+    pub fn eval(&self, mem: &[u8], eip: u64) -> Result<i64, String> {
+        Ok(match self {
+            Expr::Number(val) => *val,
+            Expr::Eip => eip as i64,
+            Expr::Word(addr) => {
+                let addr = addr.eval(mem, eip)? as u64;
+                if addr.checked_add(8).is_none_or(|end| end > mem.len() as u64) {
+                    return Err(format!("{addr:#x} is outside of memory"));
+                }
+                mem::read(mem, addr as usize)
+            }
+            Expr::Neg(inner) => inner.eval(mem, eip)?.wrapping_neg(),
+            Expr::Not(inner) => (inner.eval(mem, eip)? == 0) as i64,
+            Expr::Binary(Op::And, lhs, rhs) => {
+                (lhs.eval(mem, eip)? != 0 && rhs.eval(mem, eip)? != 0) as i64
+            }
+            Expr::Binary(Op::Or, lhs, rhs) => {
+                (lhs.eval(mem, eip)? != 0 || rhs.eval(mem, eip)? != 0) as i64
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs = lhs.eval(mem, eip)?;
+                let rhs = rhs.eval(mem, eip)?;
+                match op {
+                    Op::Add => lhs.wrapping_add(rhs),
+                    Op::Sub => lhs.wrapping_sub(rhs),
+                    Op::Eq => (lhs == rhs) as i64,
+                    Op::Ne => (lhs != rhs) as i64,
+                    Op::Lt => (lhs < rhs) as i64,
+                    Op::Le => (lhs <= rhs) as i64,
+                    Op::Gt => (lhs > rhs) as i64,
+                    Op::Ge => (lhs >= rhs) as i64,
+                    Op::And | Op::Or => unreachable!(),
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve(label: &str) -> Result<u64, String> {
+        match label {
+            "foo" => Ok(0x10),
+            "foo-bar" => Ok(0x20),
+            "a+b" => Ok(0x28),
+            _ => Err(format!("Unknown label {label}")),
+        }
+    }
+
+    fn eval(inp: &str) -> Result<i64, String> {
+        let mut mem = vec![0; 0x30];
+        mem::write(&mut mem, 0x10, &5i64.to_be_bytes());
+        mem::write(&mut mem, 0x18, &0x10i64.to_be_bytes());
+        Expr::parse(inp, &resolve)?.eval(&mem, 0x18)
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(eval("1 + 2 == 3 && 4 - 1 == 3"), Ok(1));
+        assert_eq!(eval("0 || 1 && 0"), Ok(0));
+        assert_eq!(eval("1 || 1 && 0"), Ok(1));
+        assert_eq!(eval("-1 + 2"), Ok(1));
+        assert_eq!(eval("!0 + 1"), Ok(2));
+        assert_eq!(eval("5 - 2 - 1"), Ok(2));
+        assert_eq!(eval("5 - (2 - 1)"), Ok(4));
+    }
+
+    #[test]
+    fn comparisons_do_not_chain() {
+        assert_eq!(eval("1 < 2"), Ok(1));
+        assert_eq!(eval("2 <= 1"), Ok(0));
+        assert!(eval("1 < 2 < 3").is_err());
+        assert!(eval("1 == 1 == 1").is_err());
+        assert_eq!(eval("(1 == 1) == 1"), Ok(1));
+    }
+
+    #[test]
+    fn single_equals() {
+        assert_eq!(eval("[foo] = 5"), Err("Use == for comparisons".to_owned()));
+    }
+
+    #[test]
+    fn memory() {
+        assert_eq!(eval("[foo]"), Ok(5));
+        assert_eq!(eval("[[$eip]]"), Ok(5));
+        assert_eq!(eval("[$eip + 8]"), Ok(0));
+        assert!(eval("[0x29]").is_err());
+        assert!(eval("[-1]").is_err());
+        assert!(eval("[foo").is_err());
+    }
+
+    #[test]
+    fn labels() {
+        assert_eq!(eval("foo"), Ok(0x10));
+        assert_eq!(eval("foo-bar"), Ok(0x20));
+        assert_eq!(eval("foo-bar-1"), Ok(0x1f));
+        assert_eq!(eval("foo - 1"), Ok(0xf));
+        assert_eq!(eval("foo-1"), Ok(0xf));
+        assert_eq!(eval("a+b+foo"), Ok(0x38));
+        assert!(eval("bar").is_err());
+    }
+
+    #[test]
+    fn malformed() {
+        assert!(eval("").is_err());
+        assert!(eval("1 +").is_err());
+        assert!(eval("1 2").is_err());
+        assert!(eval("(1").is_err());
+        assert!(eval("0xg").is_err());
+    }
+}
@@ -1,9 +1,14 @@
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::VecDeque,
     fs::File,
-    io::{BufWriter, Write},
-    sync::{Arc, Barrier},
+    io::{BufWriter, Read, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::RecvTimeoutError,
+        Arc, Barrier,
+    },
     thread,
+    time::{Duration, Instant},
 };
 
 use bus::Bus;
@@ -13,60 +18,378 @@ use tui::{layout::*, text::Text, widgets::*};
 
 use sync_unsafe_cell::*;
 
-use bincode::serialize_into;
 use itertools::Itertools;
 
+mod console;
 mod cpu;
-mod mem;
+mod expr;
 mod motherboard;
 mod msg;
-use noontide_emu::pdb;
+mod search;
+use noontide_emu::{coverage, loader, pdb, perf, smc, snapshot, trace};
 mod serial;
 mod sync_unsafe_cell;
+mod terminal;
 
 #[derive(Parser)]
 #[command(name = "noontide-emu")]
 #[command(author = "NyanCatTW1")]
 #[command(about = "An emulator/debugger of the Noontide SUBLEQ Computer to aid in the development of related projects", long_about = None)]
 struct Cli {
-    #[arg(help = "Base path of a program, without the .bin")]
+    #[arg(
+        help = "Base path of a program, without the .bin. Intel HEX (.ihex, .ihx) and S-record (.srec, .s19, .s28, .s37) files are loaded if there is no .bin, then .hex2, .hex1 and .hex0 sources get assembled. A path with one of these extensions is loaded as is"
+    )]
     base_path: String,
 
+    #[arg(long)]
+    #[arg(
+        help = "Write the program as it would be loaded into memory into this file and exit, e.g. to compare assemblers"
+    )]
+    write_bin: Option<String>,
+
+    #[arg(long = "load", value_name = "FILE[@ADDR]")]
+    #[arg(help = "Load a file after the program, moved up by ADDR. Can be given multiple times")]
+    loads: Vec<String>,
+
+    #[arg(long, value_parser = parse_address, conflicts_with = "snapshot")]
+    #[arg(help = "Start CPU 0 here instead of at the entry point of the program, or 0")]
+    eip: Option<u64>,
+
     #[arg(short = 'b')]
     #[arg(help = "Disable the TUI, read input from the input file, and output to stdout")]
     batch_input: Option<String>,
 
+    #[arg(long, requires = "batch_input")]
+    #[arg(
+        help = "Run debugger commands from this file in batch mode, starting paused. The program keeps running once the script ends"
+    )]
+    script: Option<String>,
+
+    #[arg(long, conflicts_with_all = ["batch_input", "script"])]
+    #[arg(
+        help = "Disable the TUI, pass the keyboard straight to serial and output to stdout. Press Ctrl+] for a debugger prompt"
+    )]
+    console: bool,
+
+    #[arg(long)]
+    #[arg(help = "Start from a snapshot saved with the snapshot save command instead of the .bin")]
+    snapshot: Option<String>,
+
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..=4096))]
+    #[arg(
+        help = "Number of CPUs. Only CPU 0 runs at first, the others start once the program sets their control blocks up"
+    )]
+    cpus: u64,
+
+    #[arg(long)]
+    #[arg(help = "Also write everything the program sends over serial into this file")]
+    serial_log: Option<String>,
+
+    #[arg(long, value_enum, requires = "serial_log")]
+    #[arg(
+        help = "Start every line of the serial log with the seconds since startup or the number of instructions executed so far"
+    )]
+    serial_log_prefix: Option<serial::LogPrefix>,
+
+    #[arg(long, value_enum)]
+    #[arg(
+        help = "What Enter and line breaks in typed, pasted or sent input become, in every mode. Defaults to crlf, or to raw with -b so that the input file reaches the program unchanged"
+    )]
+    serial_input: Option<serial::LineEnding>,
+
+    #[arg(long, value_enum, default_value_t = serial::LineEnding::Lf)]
+    #[arg(
+        help = "The line ending the program outputs, which is shown as a new line. raw makes LF only move down"
+    )]
+    serial_output: serial::LineEnding,
+
+    #[arg(long)]
+    #[arg(
+        help = "Write every byte of serial input into this file, along with the instruction count it was given to the program at"
+    )]
+    record_input: Option<String>,
+
+    #[arg(long)]
+    #[arg(
+        help = "Give the program the serial input from a --record-input file at the same instruction counts, before any other input. Exact with a single CPU"
+    )]
+    replay_input: Option<String>,
+
     #[arg(short = 'r')]
     #[arg(
         help = "Record processor EIPs into a file, which can later be analyzed with noontide-perf"
     )]
     record_path: Option<String>,
 
+    #[arg(short = 't')]
+    #[arg(
+        help = "Record every instruction executed by every CPU into a file, which can later be inspected with noontide-trace"
+    )]
+    trace_path: Option<String>,
+
+    #[arg(long)]
+    #[arg(
+        help = "Record which instructions were executed and where they branched into a file, which can later be analyzed with noontide-cov"
+    )]
+    coverage: Option<String>,
+
     #[arg(long)]
     #[arg(help = "Hide msq implementation details at and below this depth")]
     msq_depth: Option<usize>,
+
+    #[arg(long)]
+    #[arg(
+        help = "Start in single-instruction debugging mode, which can be toggled with Ctrl+D in the TUI"
+    )]
+    debug: bool,
+
+    #[arg(long)]
+    #[arg(help = "Execute code through a cache of decoded instruction blocks")]
+    blocks: bool,
+
+    #[arg(long, requires = "blocks")]
+    #[arg(help = "Execute common SUBLEQ idioms (add and move through Z) natively within blocks")]
+    idioms: bool,
+
+    // A snapshot replaces the code from the .bin, and doesn't say what of it already ran
+    #[arg(long, conflicts_with = "snapshot")]
+    #[arg(
+        help = "Report when code loaded from the .bin is modified, or executed after being modified, by the same CPU"
+    )]
+    smc: bool,
+}
+
+// Starts the search over from the top with the pattern typed so far, so that the match follows
+// the typing. Returns the pending search to run, if the search prompt is open.
+fn restart_search(
+    prompt: &Option<(Prompt, String)>,
+    search: &mut Option<search::Search>,
+) -> Option<bool> {
+    match (prompt, search) {
+        (Some((Prompt::Search, line)), Some(search)) if !line.is_empty() => {
+            search.pattern = line.clone();
+            search.line = None;
+            Some(true)
+        }
+        (Some((Prompt::Search, _)), Some(search)) => {
+            search.pattern.clear();
+            search.line = None;
+            None
+        }
+        _ => None,
+    }
+}
+
+// What the line typed into the title of the bottom window is for
+enum Prompt {
+    // Opened with Ctrl+K
+    Command,
+    // Opened with Ctrl+F
+    Search,
+}
+
+fn debug_rows(debug_entries: &VecDeque<String>) -> Vec<String> {
+    debug_entries
+        .iter()
+        .join("\r\n")
+        .lines()
+        .map(|line| line.to_owned())
+        .collect()
+}
+
+// The lines a search goes through, as they are rendered
+fn search_lines(
+    target: search::SearchTarget,
+    serial: &terminal::Terminal,
+    debug_data: &Option<pdb::DebugData>,
+    debug_entries: &VecDeque<String>,
+) -> Vec<String> {
+    match target {
+        search::SearchTarget::Serial => serial.row_texts(),
+        search::SearchTarget::Source => match debug_data {
+            Some(debug_data) => debug_data
+                .offsets
+                .iter()
+                .map(|(_, line)| line.clone())
+                .collect(),
+            None => Vec::new(),
+        },
+        search::SearchTarget::Debug => debug_rows(debug_entries),
+    }
+}
+
+fn push_console(console_lines: &mut VecDeque<String>, text: &str) {
+    for line in text.lines() {
+        console_lines.push_back(line.to_owned());
+    }
+    while console_lines.len() > 1000 {
+        console_lines.pop_front();
+    }
+}
+
+// One row per CPU with its control block status, EIP, and whether the debugger paused it
+fn render_cpu_table(mem: &[u8], cpu_states: &[Arc<cpu::CpuState>], selected_cpu: usize) -> String {
+    let mut lines = vec!["  CPU  Status        EIP                 Instructions".to_owned()];
+    for (cpu_id, state) in cpu_states.iter().enumerate() {
+        let status = noontide_emu::mem::read(mem, cpu::CPU_CONTROL_START + 16 * cpu_id);
+        let status_name = match status {
+            0 => "off",
+            1 => "running",
+            2 => "stopping",
+            4 => "stopped",
+            _ => "invalid",
+        };
+        lines.push(format!(
+            "{} {cpu_id:<4} {:<13} {:#018x}  {:<12} {}",
+            if cpu_id == selected_cpu { '>' } else { ' ' },
+            format!("{status} ({status_name})"),
+            state.eip.load(Ordering::Relaxed),
+            state.instructions.load(Ordering::Relaxed),
+            if state.paused.load(Ordering::Relaxed) {
+                "paused"
+            } else {
+                ""
+            }
+        ));
+    }
+
+    lines.join("\n")
+}
+
+fn describe_pause(
+    debug_data: &Option<pdb::DebugData>,
+    cpu_id: usize,
+    eip: u64,
+    reason: &str,
+) -> String {
+    let line = match debug_data {
+        Some(debug_data) if eip < debug_data.offsets.last().unwrap().0 => {
+            &debug_data.offsets[pdb::find_line(debug_data, eip)].1
+        }
+        _ => "(No debug info)",
+    };
+    format!("CPU {cpu_id} paused at {eip:#x}: {reason}\n  {line}")
+}
+
+// The terminal is in raw mode in console mode, which also turns off translating \n into \r\n
+fn eprint_raw(text: &str) {
+    eprint!("{}\r\n", text.replace('\n', "\r\n"));
+}
+
+// Runs commands from the script until one of them resumes the CPU. Once the script runs out,
+// the CPU is resumed for good. Returns false if the script quits.
+fn run_script(
+    script: &mut VecDeque<String>,
+    console: &mut console::Console,
+    mem: &mut [u8],
+) -> bool {
+    while let Some(line) = script.pop_front() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        eprintln!("> {line}");
+        match console.execute(line, mem) {
+            Ok(console::Outcome::Output(out)) => eprintln!("{out}"),
+            Ok(console::Outcome::Resumed) => return true,
+            Ok(console::Outcome::Quit) => return false,
+            Err(e) => eprintln!("Error: {e}"),
+        }
+    }
+
+    let _ = console.execute("continue", mem);
+    true
+}
+
+fn parse_address(inp: &str) -> Result<u64, String> {
+    console::parse_number(inp).map(|addr| addr as u64)
+}
+
+fn exit_on_error<T>(result: Result<T, String>) -> T {
+    result.unwrap_or_else(|e| {
+        eprintln!("Error: {e}");
+        std::process::exit(1);
+    })
 }
 
 fn main() {
     let cli = Cli::parse();
-    let base_path = cli.base_path;
 
-    // Load the .bin file into mem
+    // Load the program into mem
     let mut mem = vec![0u8; 0x14000000];
-    let mut bin_path = base_path.clone();
-    bin_path.push_str(".bin");
-    let data = std::fs::read(bin_path).unwrap();
-    mem[..data.len()].copy_from_slice(&data);
+    let (base_path, mut program) = exit_on_error(loader::load_program(&cli.base_path));
+    exit_on_error(program.load_into(&mut mem, 0));
+    let bin_len = program.end();
+    let bin_hash = program.hash();
+
+    if let Some(write_bin_path) = &cli.write_bin {
+        std::fs::write(write_bin_path, &mem[..bin_len as usize]).unwrap();
+        return;
+    }
+
+    // The block cache only notices writes made by the CPU it belongs to
+    let cpu_count = cli.cpus as usize;
+    if cpu_count > 1 && cli.blocks {
+        eprintln!("Error: --blocks only supports a single CPU");
+        std::process::exit(1);
+    }
+
+    let mut initial_eips = vec![0; cpu_count];
+    initial_eips[0] = cli.eip.or(program.entry).unwrap_or(0);
+    if let Some(snapshot_path) = &cli.snapshot {
+        mem.fill(0);
+        match snapshot::read(snapshot_path, &mut mem) {
+            Ok(eips) => {
+                for (initial_eip, eip) in initial_eips.iter_mut().zip(eips) {
+                    *initial_eip = eip;
+                }
+            }
+            Err(e) => {
+                eprintln!("Error: {e}");
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // Blobs go on top of the program or the snapshot
+    for load in &cli.loads {
+        let (path, offset) = match load.rsplit_once('@') {
+            Some((path, addr)) => (path, exit_on_error(parse_address(addr))),
+            None => (load.as_str(), 0),
+        };
+        let blob = exit_on_error(loader::read(path));
+        exit_on_error(blob.load_into(&mut mem, offset));
+    }
 
-    let debug_data = pdb::find_debug_data(&base_path, cli.msq_depth.unwrap_or(100));
+    // An .lsq takes priority as with find_debug_data(), then the lines of an assembled
+    // program, which match its bytes exactly
+    let lsq_path = format!("{base_path}.lsq");
+    let debug_data = (!std::path::Path::new(&lsq_path).exists())
+        .then(|| program.debug_data.take())
+        .flatten()
+        .or_else(|| pdb::find_debug_data(&base_path, cli.msq_depth.unwrap_or(100)))
+        // An empty source file has no lines to show
+        .filter(|debug_data| !debug_data.offsets.is_empty());
     let record_eips = cli.record_path.is_some();
-    let mut recorded_eips: HashMap<u64, u64> = HashMap::new();
 
     // Set up the Arcs
     let mut handles = vec![];
+    let mem_len = mem.len();
     let mem_arc = Arc::new(SyncUnsafeCell::new(mem));
+    let debug_enabled_arc = Arc::new(AtomicBool::new(cli.debug));
+    let cpu_states: Vec<Arc<cpu::CpuState>> = (0..cpu_count)
+        .map(|cpu_id| {
+            // Scripts can rely on CPU 0 being paused before it ran its first cycle
+            Arc::new(cpu::CpuState {
+                paused: AtomicBool::new(cli.script.is_some() && cpu_id == 0),
+                ..Default::default()
+            })
+        })
+        .collect();
     let io_barrier_arc = Arc::new(Barrier::new(2));
-    let cpu_barrier_arc = Arc::new(Barrier::new(2));
+    // The CPUs and the Motherboard
+    let cpu_barrier_arc = Arc::new(Barrier::new(cpu_count + 1));
 
     // Set up the mpsc channels
     let (ui_sender, ui_receiver) = std::sync::mpsc::channel();
@@ -77,10 +400,32 @@ fn main() {
     // Set up the broadcast bus for stopping threads
     let mut term_tx: Bus<usize> = Bus::new(10);
     let term_rx_serial = term_tx.add_rx();
-    let term_rx_cpu0 = term_tx.add_rx();
 
     // Start the Serial thread
     {
+        let replay = match &cli.replay_input {
+            Some(replay_path) => match serial::read_input_log(replay_path) {
+                Ok(replay) => replay,
+                Err(e) => {
+                    eprintln!("Error: {e}");
+                    std::process::exit(1);
+                }
+            },
+            None => VecDeque::new(),
+        };
+        let options = serial::SerialOptions {
+            input_ending: cli.serial_input.unwrap_or(if cli.batch_input.is_some() {
+                serial::LineEnding::Raw
+            } else {
+                serial::LineEnding::Crlf
+            }),
+            record: cli
+                .record_input
+                .as_ref()
+                .map(|record_path| BufWriter::new(File::create(record_path).unwrap())),
+            replay,
+            cpu_states: cpu_states.clone(),
+        };
         let mem = Arc::clone(&mem_arc);
         let io_barrier = Arc::clone(&io_barrier_arc);
         let sender = ui_sender.clone();
@@ -94,46 +439,69 @@ fn main() {
                         sender,
                         serial_receiver,
                         term_rx_serial,
+                        options,
                     )
                 })
                 .unwrap(),
         );
     }
 
-    #[cfg(feature = "debugger")]
-    let mut cycle_length = 1;
+    // Cycles end early whenever a device is accessed, so this only bounds how long the
+    // devices and the CPU control blocks can go unchecked
+    let cycle_length = 65536;
+    let sample_interval = 100;
 
-    //  512: 16.3575 +- 0.0710 seconds time elapsed  ( +-  0.43% )
-    // 1024: 16.3151 +- 0.0483 seconds time elapsed  ( +-  0.30% )
-    // 2048: 16.4346 +- 0.0623 seconds time elapsed  ( +-  0.38% )
-    // 4096: 16.5468 +- 0.0562 seconds time elapsed  ( +-  0.34% )
-    #[cfg(not(feature = "debugger"))]
-    let mut cycle_length = 4096;
-
-    if record_eips {
-        cycle_length = std::cmp::min(cycle_length, 100);
-    }
-
-    // Start the CPU 0 thread
-    {
+    // Start the CPU threads
+    let trace_writer = cli
+        .trace_path
+        .as_ref()
+        .map(|trace_path| trace::TraceWriter::create(trace_path, bin_hash));
+    let mut cpu_senders = Vec::new();
+    for (cpu_id, initial_eip) in initial_eips.into_iter().enumerate() {
         let mem = Arc::clone(&mem_arc);
         let cpu_barrier = Arc::clone(&cpu_barrier_arc);
+        let (cpu_sender, cpu_receiver) = std::sync::mpsc::channel();
+        cpu_senders.push(cpu_sender);
+        let options = cpu::CpuOptions {
+            initial_eip,
+            cycle_length,
+            sample_interval: record_eips.then_some(sample_interval),
+            debug_enabled: Arc::clone(&debug_enabled_arc),
+            trace: trace_writer.as_ref().map(trace::TraceWriter::share),
+            blocks: cli.blocks,
+            idioms: cli.idioms,
+            smc: cli
+                .smc
+                .then(|| smc::SmcTracker::for_program(bin_len, &debug_data)),
+            coverage: cli
+                .coverage
+                .is_some()
+                .then(|| coverage::CoverageRecorder::new(mem_len)),
+            commands: cpu_receiver,
+            start_paused: cli.script.is_some() && cpu_id == 0,
+        };
+        let state = Arc::clone(&cpu_states[cpu_id]);
+        let sender = ui_sender.clone();
+        let term_rx = term_tx.add_rx();
         handles.push(
             thread::Builder::new()
-                .name("CPU 0".to_string())
+                .name(format!("CPU {cpu_id}"))
                 .spawn(move || {
                     cpu::cpu_loop(
                         unsafe { mem.get().as_mut().unwrap() },
-                        0,
+                        cpu_id,
                         cpu_barrier,
-                        ui_sender,
-                        term_rx_cpu0,
-                        cycle_length,
+                        sender,
+                        term_rx,
+                        options,
+                        state,
                     )
                 })
                 .unwrap(),
         );
     }
+    // The file is finished once the last CPU is done with it
+    drop(trace_writer);
 
     // Start the Motherboard thread
     {
@@ -154,8 +522,179 @@ fn main() {
         );
     }
 
+    let mut serial_log = cli
+        .serial_log
+        .as_ref()
+        .map(|serial_log_path| serial::SerialLog::create(serial_log_path, cli.serial_log_prefix));
+
+    // Line endings are only translated for display, the serial log gets what was sent
+    let mut output_translator = serial::Translator::new(cli.serial_output);
+    let mut console = console::Console::new(
+        cpu_senders,
+        cpu_states.clone(),
+        serial_sender.clone(),
+        &debug_data,
+    );
     let mut cpus_running = 1;
     match cli.batch_input {
+        None if cli.console => {
+            // Like telnet
+            const ESCAPE: u8 = 0x1d;
+
+            let original_hook = std::panic::take_hook();
+            std::panic::set_hook(Box::new(move |panic| {
+                let _ = crossterm::terminal::disable_raw_mode();
+                original_hook(panic);
+            }));
+
+            // Reads block, so this thread is left behind on exit instead of being joined
+            let (stdin_sender, stdin_receiver) = std::sync::mpsc::channel();
+            thread::Builder::new()
+                .name("Stdin".to_string())
+                .spawn(move || {
+                    let mut stdin = std::io::stdin().lock();
+                    let mut byte = 0u8;
+                    while let Ok(1) = stdin.read(std::slice::from_mut(&mut byte)) {
+                        if stdin_sender.send(byte).is_err() {
+                            return;
+                        }
+                    }
+                })
+                .unwrap();
+
+            eprintln!("Press Ctrl+] for a debugger prompt");
+            crossterm::terminal::enable_raw_mode().unwrap();
+
+            let mem = unsafe { mem_arc.get().as_mut().unwrap() };
+            // The prompt uses the terminal in cooked mode, so that lines can be edited
+            let mut at_prompt = false;
+            let mut show_prompt = false;
+            let mut prompt_shown = false;
+            let mut line = Vec::new();
+            let mut last_line = (0, -1);
+            'console: loop {
+                match ui_receiver.recv_timeout(Duration::from_millis(10)) {
+                    Ok(msg::UIMessage::Debug(cpu_id, eip, dat)) => {
+                        let (cur_line, debug_print) = pdb::render_debug(&debug_data, eip, 2, true);
+                        if cur_line == -1 || (cpu_id, cur_line) != last_line {
+                            eprint_raw(&format!("CPU {cpu_id}: {}\n{}\n", dat, debug_print));
+                        }
+                        last_line = (cpu_id, cur_line);
+                    }
+                    Ok(msg::UIMessage::Serial(c, instructions)) => {
+                        if let Some(serial_log) = &mut serial_log {
+                            serial_log.write(c, instructions);
+                        }
+
+                        let mut stdout = std::io::stdout();
+                        output_translator.output(c, |c| {
+                            if c == b'\n'
+                                && !at_prompt
+                                && cli.serial_output != serial::LineEnding::Raw
+                            {
+                                stdout.write_all(b"\r").unwrap();
+                            }
+                            stdout.write_all(std::slice::from_ref(&c)).unwrap();
+                        });
+                        stdout.flush().unwrap();
+                    }
+                    Ok(msg::UIMessage::CPUStarted(_cpu_id)) => {
+                        cpus_running += 1;
+                    }
+                    Ok(msg::UIMessage::CPUStopped(_cpu_id)) => {
+                        cpus_running -= 1;
+                        if cpus_running == 0 {
+                            break;
+                        }
+                    }
+                    Ok(msg::UIMessage::Smc(_cpu_id, event)) => {
+                        eprint_raw(&format!("{}\n", event.describe(&debug_data)));
+                    }
+                    Ok(msg::UIMessage::Log(cpu_id, eip, out)) => {
+                        eprint_raw(&format!("CPU {cpu_id} at {eip:#x}: {out}"));
+                    }
+                    Ok(msg::UIMessage::Paused(cpu_id, eip, reason)) => {
+                        if !at_prompt {
+                            crossterm::terminal::disable_raw_mode().unwrap();
+                            eprintln!();
+                            at_prompt = true;
+                        } else if prompt_shown {
+                            // Already waiting for a command, so start on a new line
+                            eprintln!();
+                        }
+                        eprintln!("{}", describe_pause(&debug_data, cpu_id, eip, &reason));
+                        console.select_cpu(cpu_id);
+                        if console.has_variables() {
+                            eprintln!("{}", console.render_variables(mem));
+                        }
+                        show_prompt = true;
+                    }
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => panic!("ui_receiver failed"),
+                }
+                if let Some(done) = console.pump_transfer() {
+                    eprint_raw(&done);
+                }
+
+                loop {
+                    let byte = match stdin_receiver.try_recv() {
+                        Ok(byte) => byte,
+                        Err(std::sync::mpsc::TryRecvError::Empty) => break,
+                        // Nothing more can be typed at the prompt
+                        Err(std::sync::mpsc::TryRecvError::Disconnected) if at_prompt => {
+                            break 'console;
+                        }
+                        Err(std::sync::mpsc::TryRecvError::Disconnected) => break,
+                    };
+
+                    if !at_prompt {
+                        if byte == ESCAPE {
+                            crossterm::terminal::disable_raw_mode().unwrap();
+                            eprintln!();
+                            let _ = console.execute("pause", mem);
+                            at_prompt = true;
+                            show_prompt = true;
+                        } else {
+                            serial_sender.send(byte).unwrap();
+                        }
+                        continue;
+                    }
+
+                    if byte != b'\n' {
+                        line.push(byte);
+                        continue;
+                    }
+
+                    let command = String::from_utf8_lossy(&line).trim().to_owned();
+                    line.clear();
+                    show_prompt = true;
+                    prompt_shown = false;
+                    if command.is_empty() {
+                        continue;
+                    }
+                    match console.execute(&command, mem) {
+                        Ok(console::Outcome::Output(out)) => eprintln!("{out}"),
+                        Ok(console::Outcome::Resumed) => {
+                            crossterm::terminal::enable_raw_mode().unwrap();
+                            at_prompt = false;
+                            show_prompt = false;
+                        }
+                        Ok(console::Outcome::Quit) => break 'console,
+                        Err(e) => eprintln!("Error: {e}"),
+                    }
+                }
+
+                if at_prompt && show_prompt {
+                    eprint!("> ");
+                    show_prompt = false;
+                    prompt_shown = true;
+                }
+            }
+
+            if !at_prompt {
+                crossterm::terminal::disable_raw_mode().unwrap();
+            }
+        }
         None => {
             // Make crossterm exit itself upon panic
             let original_hook = std::panic::take_hook();
@@ -165,6 +704,7 @@ fn main() {
                     std::io::stdout(),
                     crossterm::terminal::LeaveAlternateScreen,
                     crossterm::event::DisableMouseCapture,
+                    crossterm::event::DisableBracketedPaste,
                 )
                 .unwrap();
                 original_hook(panic);
@@ -177,6 +717,7 @@ fn main() {
                 stdout,
                 crossterm::terminal::EnterAlternateScreen,
                 crossterm::event::EnableMouseCapture,
+                crossterm::event::EnableBracketedPaste,
             )
             .unwrap();
 
@@ -184,38 +725,73 @@ fn main() {
             let mut terminal = tui::Terminal::new(backend).unwrap();
             terminal.show_cursor().unwrap();
 
-            let mut code_out = "CPU 0 is still starting...".to_owned();
-            let mut debug_entries = VecDeque::new();
+            // One list per CPU
+            let mut debug_entries = vec![VecDeque::new(); cpu_count];
 
+            // Code and Debug show the CPU selected with Tab, which is also the one the console
+            // steps
             let mut cur_window = 0;
-            let window_names = vec!["Code", "Memory Dump", "Debug (CPU 0)"];
+            let window_names = [
+                "Code",
+                "Memory Dump",
+                "Debug",
+                "Variables",
+                "CPUs",
+                "Console",
+            ];
             let window_types = window_names.len();
+            let console_window = 5;
+
+            let mut prompt: Option<(Prompt, String)> = None;
+            let mut console_lines = VecDeque::new();
+            let mut search: Option<search::Search> = None;
+            // Shown in the title of the bottom window until the next key press
+            let mut notice: Option<String> = None;
+            // Top line of the Code window while browsing the whole source with Ctrl+B
+            let mut code_browse: Option<usize> = None;
 
             let mut scroll = (0, 0);
-            let mut previous_char = '\0';
             let debug_lines: usize = 10;
 
-            let mut serial_out = String::new();
+            // Resized to the Serial pane before every frame
+            let mut serial =
+                terminal::Terminal::new(80, 24, cli.serial_output != serial::LineEnding::Raw);
+            // Rows scrolled back from the bottom of the Serial pane, 0 follows the output
+            let mut serial_scroll: usize = 0;
+            let frame_duration = Duration::from_millis(1000 / 30);
             'main: loop {
-                if let Ok(msg) = ui_receiver.recv() {
+                // Handle messages until the next frame is due
+                let frame_deadline = Instant::now() + frame_duration;
+                loop {
+                    let timeout = frame_deadline.saturating_duration_since(Instant::now());
+                    let msg = match ui_receiver.recv_timeout(timeout) {
+                        Ok(msg) => msg,
+                        Err(RecvTimeoutError::Timeout) => break,
+                        Err(RecvTimeoutError::Disconnected) => panic!("ui_receiver failed"),
+                    };
+
                     match msg {
-                        msg::UIMessage::Serial(c) => {
-                            serial_out.push(c as char);
-                        }
-                        msg::UIMessage::SetEIP(eip) => {
-                            code_out = pdb::render_debug(
-                                &debug_data,
-                                eip,
-                                (debug_lines / 2) as usize,
-                                false,
-                            )
-                            .1;
-
-                            if record_eips {
-                                *recorded_eips.entry(eip).or_insert(0) += 1;
+                        msg::UIMessage::Serial(c, instructions) => {
+                            if let Some(serial_log) = &mut serial_log {
+                                serial_log.write(c, instructions);
                             }
+
+                            output_translator.output(c, |c| {
+                                let (scrolled, dropped) = serial.push(c);
+                                // Keep the rows in view where they are
+                                if scrolled && serial_scroll != 0 && !dropped {
+                                    serial_scroll += 1;
+                                }
+                                if let (true, Some(search)) = (dropped, &mut search) {
+                                    if search.target == search::SearchTarget::Serial {
+                                        search.line =
+                                            search.line.and_then(|line| line.checked_sub(1));
+                                    }
+                                }
+                            });
                         }
-                        msg::UIMessage::Debug(_eip, str) => {
+                        msg::UIMessage::Debug(cpu_id, _eip, str) => {
+                            let debug_entries = &mut debug_entries[cpu_id];
                             debug_entries.push_back(str);
                             if debug_entries.len() > debug_lines {
                                 debug_entries.pop_front();
@@ -227,32 +803,129 @@ fn main() {
                         msg::UIMessage::CPUStopped(_cpu_id) => {
                             cpus_running -= 1;
                             if cpus_running == 0 {
-                                // Exit crossterm cleanly
-                                crossterm::terminal::disable_raw_mode().unwrap();
-                                crossterm::execute!(
-                                    terminal.backend_mut(),
-                                    crossterm::terminal::LeaveAlternateScreen,
-                                    crossterm::event::DisableMouseCapture,
-                                    crossterm::event::DisableBracketedPaste
-                                )
-                                .unwrap();
-                                terminal.show_cursor().unwrap();
-
-                                break;
+                                break 'main;
+                            }
+                        }
+                        msg::UIMessage::Smc(cpu_id, event) => {
+                            let debug_entries = &mut debug_entries[cpu_id];
+                            debug_entries.push_back(event.describe(&debug_data));
+                            if debug_entries.len() > debug_lines {
+                                debug_entries.pop_front();
                             }
                         }
+                        msg::UIMessage::Log(cpu_id, eip, out) => {
+                            push_console(
+                                &mut console_lines,
+                                &format!("CPU {cpu_id} at {eip:#x}: {out}"),
+                            );
+                        }
+                        msg::UIMessage::Paused(cpu_id, eip, reason) => {
+                            console.select_cpu(cpu_id);
+                            push_console(
+                                &mut console_lines,
+                                &describe_pause(&debug_data, cpu_id, eip, &reason),
+                            );
+                        }
                     }
-                } else {
-                    panic!("ui_receiver failed");
+                }
+                if let Some(done) = console.pump_transfer() {
+                    notice = Some(done);
                 }
 
                 {
-                    let serial_out = serial_out.clone();
-                    let code_out = code_out.clone();
-                    let mem_out = pdb::memory_dump(unsafe { mem_arc.get().as_ref().unwrap() });
-                    let debug_out = debug_entries.iter().join("\r\n");
+                    let highlighted = |target| {
+                        search
+                            .as_ref()
+                            .filter(|search| search.target == target)
+                            .and_then(|search| search.line)
+                    };
+                    let height = terminal.size().unwrap().height as usize;
+
+                    // Only the rows that fit are built, a match in the serial output is shown at
+                    // the top
+                    let size = terminal.size().unwrap();
+                    let serial_area = Layout::default()
+                        .constraints([Constraint::Percentage(50), Constraint::Percentage(50)])
+                        .split(size)[0];
+                    let serial_height = serial_area.height.saturating_sub(2) as usize;
+                    serial.resize(serial_area.width.saturating_sub(2) as usize, serial_height);
+                    let serial_match = highlighted(search::SearchTarget::Serial);
+                    let serial_top = match serial_match {
+                        Some(row) => row,
+                        None => serial.history().saturating_sub(serial_scroll),
+                    };
+                    let serial_out = serial.render(serial_top, serial_height, serial_match);
+                    let mut serial_title = if serial_scroll != 0 && serial_match.is_none() {
+                        format!("Serial ({serial_scroll} rows back, Shift+Down to follow)")
+                    } else {
+                        "Serial".to_owned()
+                    };
+                    if let Some(progress) = console.transfer_progress() {
+                        serial_title.push_str(&format!(" - {progress}"));
+                    }
+                    let selected_cpu = console.selected_cpu();
+                    let state = &cpu_states[selected_cpu];
+                    let eip = state.eip.load(Ordering::Relaxed);
+                    let code_out = match (code_browse, &debug_data) {
+                        (Some(top), Some(debug_data)) => search::highlight(
+                            pdb::render_source(debug_data, eip, top, height)
+                                .into_iter()
+                                .enumerate(),
+                            highlighted(search::SearchTarget::Source)
+                                .and_then(|line| line.checked_sub(top)),
+                        ),
+                        _ if state.instructions.load(Ordering::Relaxed) == 0 => {
+                            Text::from(format!("CPU {selected_cpu} has not started yet..."))
+                        }
+                        _ => Text::from(
+                            pdb::render_debug(&debug_data, eip, debug_lines / 2, false).1,
+                        ),
+                    };
+                    // Only build the memory dump when it is visible
+                    let mem_out = if cur_window == 1 {
+                        pdb::memory_dump(unsafe { mem_arc.get().as_ref().unwrap() })
+                    } else {
+                        "".to_owned()
+                    };
+                    let variables_out = if cur_window == 3 {
+                        console.render_variables(unsafe { mem_arc.get().as_ref().unwrap() })
+                    } else {
+                        "".to_owned()
+                    };
+                    let cpus_out = if cur_window == 4 {
+                        render_cpu_table(
+                            unsafe { mem_arc.get().as_ref().unwrap() },
+                            &cpu_states,
+                            selected_cpu,
+                        )
+                    } else {
+                        "".to_owned()
+                    };
+                    let debug_out = search::highlight(
+                        debug_rows(&debug_entries[selected_cpu])
+                            .into_iter()
+                            .enumerate(),
+                        highlighted(search::SearchTarget::Debug),
+                    );
+                    let console_out: Vec<String> = console_lines.iter().cloned().collect();
 
-                    let window_name = window_names[cur_window];
+                    let window_name = match (&prompt, &notice) {
+                        (Some((Prompt::Command, line)), _) => format!("Command: {line}_"),
+                        (Some((Prompt::Search, line)), _) => {
+                            let found = line.is_empty()
+                                || search.as_ref().is_some_and(|search| search.line.is_some());
+                            let status = if found { "" } else { " (not found)" };
+                            format!("Search: {line}_{status}")
+                        }
+                        (None, Some(notice)) => notice.clone(),
+                        (None, None) if cur_window == 0 && code_browse.is_some() => {
+                            format!("Source (CPU {selected_cpu})")
+                        }
+                        (None, None) if cur_window == 0 || cur_window == 2 => {
+                            format!("{} (CPU {selected_cpu})", window_names[cur_window])
+                        }
+                        (None, None) => window_names[cur_window].to_owned(),
+                    };
                     terminal
                         .draw(move |f| {
                             let chunks = Layout::default()
@@ -262,10 +935,9 @@ fn main() {
                                 ])
                                 .split(f.size());
 
-                            let block = Block::default().title("Serial").borders(Borders::ALL);
+                            let block = Block::default().title(serial_title).borders(Borders::ALL);
                             f.render_widget(block, chunks[0]);
-                            let p =
-                                Paragraph::new(Text::from(serial_out)).wrap(Wrap { trim: false });
+                            let p = Paragraph::new(serial_out);
                             f.render_widget(
                                 p,
                                 chunks[0].inner(&Margin {
@@ -278,17 +950,27 @@ fn main() {
                             f.render_widget(block, chunks[1]);
 
                             let p = if cur_window == 0 {
-                                Paragraph::new(Text::from(code_out))
+                                Paragraph::new(code_out)
                                     .wrap(Wrap { trim: false })
                                     .scroll(scroll)
                             } else if cur_window == 1 {
                                 Paragraph::new(Text::from(mem_out))
                                     .wrap(Wrap { trim: false })
                                     .scroll(scroll)
-                            } else {
-                                Paragraph::new(Text::from(debug_out))
+                            } else if cur_window == 2 {
+                                Paragraph::new(debug_out)
                                     .wrap(Wrap { trim: false })
                                     .scroll(scroll)
+                            } else if cur_window == 3 {
+                                Paragraph::new(Text::from(variables_out)).scroll(scroll)
+                            } else if cur_window == 4 {
+                                Paragraph::new(Text::from(cpus_out)).scroll(scroll)
+                            } else {
+                                // Stick to the bottom, scrolling goes back in history
+                                let height = chunks[1].height.saturating_sub(2) as usize;
+                                let end = console_out.len().saturating_sub(scroll.0 as usize);
+                                let start = end.saturating_sub(height);
+                                Paragraph::new(Text::from(console_out[start..end].join("\n")))
                             };
 
                             f.render_widget(
@@ -302,14 +984,155 @@ fn main() {
                         .unwrap();
                 }
 
-                while crossterm::event::poll(std::time::Duration::from_millis(10)).unwrap() {
-                    match crossterm::event::read().unwrap() {
+                // Some(forward) once the search should move to the next or previous match
+                let mut pending_search = None;
+                while crossterm::event::poll(Duration::ZERO).unwrap() {
+                    let event = crossterm::event::read().unwrap();
+                    if let Event::Key(_) = event {
+                        notice = None;
+                    }
+
+                    match event {
+                        Event::Key(key) if prompt.is_some() => match key.code {
+                            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                                break 'main;
+                            }
+                            KeyCode::Esc => {
+                                if let Some((Prompt::Search, _)) = prompt.take() {
+                                    search = None;
+                                }
+                            }
+                            KeyCode::Backspace => {
+                                prompt.as_mut().unwrap().1.pop();
+                                pending_search =
+                                    restart_search(&prompt, &mut search).or(pending_search);
+                            }
+                            KeyCode::Enter => match prompt.take().unwrap() {
+                                (Prompt::Command, line) => {
+                                    push_console(&mut console_lines, &format!("> {line}"));
+                                    let mem = unsafe { mem_arc.get().as_mut().unwrap() };
+                                    match console.execute(&line, mem) {
+                                        Ok(console::Outcome::Output(out)) => {
+                                            push_console(&mut console_lines, &out);
+                                        }
+                                        Ok(console::Outcome::Resumed) => {}
+                                        Ok(console::Outcome::Quit) => break 'main,
+                                        Err(e) => {
+                                            push_console(
+                                                &mut console_lines,
+                                                &format!("Error: {e}"),
+                                            );
+                                        }
+                                    }
+
+                                    cur_window = console_window;
+                                    scroll = (0, 0);
+                                }
+                                // Lands on the match already found while typing, again, to tell
+                                // where it is
+                                (Prompt::Search, pattern) => match &mut search {
+                                    Some(search) if !pattern.is_empty() => {
+                                        search.line = None;
+                                        pending_search = Some(true);
+                                    }
+                                    _ => search = None,
+                                },
+                            },
+                            KeyCode::Char(c) => {
+                                prompt.as_mut().unwrap().1.push(c);
+                                pending_search =
+                                    restart_search(&prompt, &mut search).or(pending_search);
+                            }
+                            _ => {}
+                        },
                         Event::Key(key) => match key.code {
                             KeyCode::Esc | KeyCode::Char('c')
                                 if key.modifiers.contains(KeyModifiers::CONTROL) =>
                             {
                                 break 'main;
                             }
+                            KeyCode::Char('k') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                                prompt = Some((Prompt::Command, String::new()));
+                            }
+                            KeyCode::Char('f') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                                prompt = Some((Prompt::Search, String::new()));
+                                // The bottom window decides what gets searched
+                                search = Some(search::Search {
+                                    pattern: String::new(),
+                                    target: match cur_window {
+                                        0 => search::SearchTarget::Source,
+                                        2 => search::SearchTarget::Debug,
+                                        _ => search::SearchTarget::Serial,
+                                    },
+                                    line: None,
+                                });
+                            }
+                            KeyCode::Char('n') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                                pending_search = Some(true);
+                            }
+                            KeyCode::Char('p') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                                pending_search = Some(false);
+                            }
+                            KeyCode::Char('b') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                                code_browse = match (code_browse, &debug_data) {
+                                    (None, Some(debug_data)) => {
+                                        let eip = cpu_states[console.selected_cpu()]
+                                            .eip
+                                            .load(Ordering::Relaxed);
+                                        Some(
+                                            pdb::find_line(debug_data, eip)
+                                                .saturating_sub(debug_lines / 2),
+                                        )
+                                    }
+                                    _ => None,
+                                };
+                                cur_window = 0;
+                                scroll = (0, 0);
+                            }
+                            KeyCode::Esc => {
+                                search = None;
+                                code_browse = None;
+                            }
+                            KeyCode::Up | KeyCode::PageUp
+                                if key.modifiers.contains(KeyModifiers::SHIFT) =>
+                            {
+                                let lines = if key.code == KeyCode::Up { 1 } else { 20 };
+                                serial_scroll =
+                                    std::cmp::min(serial_scroll + lines, serial.history());
+                            }
+                            KeyCode::Down | KeyCode::PageDown
+                                if key.modifiers.contains(KeyModifiers::SHIFT) =>
+                            {
+                                let lines = if key.code == KeyCode::Down { 1 } else { 20 };
+                                serial_scroll = serial_scroll.saturating_sub(lines);
+                            }
+                            KeyCode::Up | KeyCode::PageUp
+                                if cur_window == 0 && code_browse.is_some() =>
+                            {
+                                let lines = if key.code == KeyCode::Up { 1 } else { 20 };
+                                let top = code_browse.as_mut().unwrap();
+                                *top = top.saturating_sub(lines);
+                            }
+                            KeyCode::Down | KeyCode::PageDown
+                                if cur_window == 0 && code_browse.is_some() =>
+                            {
+                                let lines = if key.code == KeyCode::Down { 1 } else { 20 };
+                                let last =
+                                    debug_data.as_ref().unwrap().offsets.len().saturating_sub(1);
+                                let top = code_browse.as_mut().unwrap();
+                                *top = std::cmp::min(*top + lines, last);
+                            }
+                            KeyCode::Char('d') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                                debug_enabled_arc.fetch_xor(true, Ordering::Relaxed);
+                            }
+                            KeyCode::Tab => {
+                                console.select_cpu((console.selected_cpu() + 1) % cpu_count);
+                            }
+                            KeyCode::BackTab => {
+                                console.select_cpu(
+                                    (console.selected_cpu() + cpu_count - 1) % cpu_count,
+                                );
+                            }
                             KeyCode::Left => {
                                 scroll = (0, 0);
                                 if cur_window != 0 {
@@ -323,34 +1146,56 @@ fn main() {
                                 cur_window = (cur_window + 1) % window_types
                             }
                             KeyCode::Up => {
-                                if scroll.0 != 0 {
-                                    scroll.0 -= 1;
-                                }
+                                scroll.0 = scroll.0.saturating_sub(1);
                             }
                             KeyCode::Down => {
                                 scroll.0 += 1;
                             }
+                            // Like a terminal, the Serial thread applies --serial-input
                             KeyCode::Enter => {
-                                // serial_out.push_str("\r\n");
-                                serial_sender.send('\r').unwrap();
-                                serial_sender.send('\n').unwrap();
+                                serial_sender.send(b'\r').unwrap();
                             }
+                            // Serial takes bytes, so anything outside of ASCII goes as UTF-8
                             KeyCode::Char(c) => {
-                                if c == '\n' && previous_char != '\r' {
-                                    // serial_out.push('\r');
-                                    serial_sender.send('\r').unwrap();
+                                for &byte in c.encode_utf8(&mut [0; 4]).as_bytes() {
+                                    serial_sender.send(byte).unwrap();
                                 }
-                                // serial_out.push(c);
-                                serial_sender.send(c).unwrap();
-                                previous_char = c;
                             }
                             _ => {}
                         },
+                        Event::Paste(text) => match &mut prompt {
+                            Some((_, line)) => {
+                                line.extend(text.chars().filter(|&c| c != '\r' && c != '\n'));
+                            }
+                            None => {
+                                for byte in text.bytes() {
+                                    serial_sender.send(byte).unwrap();
+                                }
+                            }
+                        },
                         Event::Mouse(e) => {
-                            if let MouseEventKind::ScrollUp = e.kind {
-                                if scroll.0 != 0 {
-                                    scroll.0 -= 1;
+                            let serial_pane = e.row < terminal.size().unwrap().height / 2;
+                            if serial_pane {
+                                if let MouseEventKind::ScrollUp = e.kind {
+                                    serial_scroll =
+                                        std::cmp::min(serial_scroll + 1, serial.history());
+                                } else if let MouseEventKind::ScrollDown = e.kind {
+                                    serial_scroll = serial_scroll.saturating_sub(1);
+                                }
+                            } else if let (0, Some(top)) = (cur_window, &mut code_browse) {
+                                if let MouseEventKind::ScrollUp = e.kind {
+                                    *top = top.saturating_sub(1);
+                                } else if let MouseEventKind::ScrollDown = e.kind {
+                                    let last = debug_data
+                                        .as_ref()
+                                        .unwrap()
+                                        .offsets
+                                        .len()
+                                        .saturating_sub(1);
+                                    *top = std::cmp::min(*top + 1, last);
                                 }
+                            } else if let MouseEventKind::ScrollUp = e.kind {
+                                scroll.0 = scroll.0.saturating_sub(1);
                             } else if let MouseEventKind::ScrollDown = e.kind {
                                 scroll.0 += 1;
                             }
@@ -358,36 +1203,94 @@ fn main() {
                         _ => {}
                     }
                 }
+
+                if let (Some(forward), Some(cur_search)) = (pending_search, &mut search) {
+                    let lines = search_lines(
+                        cur_search.target,
+                        &serial,
+                        &debug_data,
+                        &debug_entries[console.selected_cpu()],
+                    );
+                    if cur_search.advance(&lines, forward) {
+                        let line = cur_search.line.unwrap();
+                        match cur_search.target {
+                            search::SearchTarget::Source => {
+                                code_browse = Some(line.saturating_sub(debug_lines / 2));
+                                cur_window = 0;
+                                scroll = (0, 0);
+                            }
+                            search::SearchTarget::Debug => {
+                                cur_window = 2;
+                                scroll = (line as u16, 0);
+                            }
+                            search::SearchTarget::Serial => {}
+                        }
+                        notice = Some(format!(
+                            "Search: {} (line {}, Ctrl+N/Ctrl+P for next/previous, Esc to stop)",
+                            cur_search.pattern,
+                            line + 1
+                        ));
+                    } else {
+                        notice = Some(format!("Pattern not found: {}", cur_search.pattern));
+                    }
+                }
             }
+
+            // Exit crossterm cleanly
+            crossterm::terminal::disable_raw_mode().unwrap();
+            crossterm::execute!(
+                terminal.backend_mut(),
+                crossterm::terminal::LeaveAlternateScreen,
+                crossterm::event::DisableMouseCapture,
+                crossterm::event::DisableBracketedPaste
+            )
+            .unwrap();
+            terminal.show_cursor().unwrap();
         }
         Some(batch_input) => {
             let input_data = std::fs::read(batch_input).unwrap();
             for chr in input_data {
-                serial_sender.send(chr as char).unwrap();
+                serial_sender.send(chr).unwrap();
             }
 
-            let mut last_line = -1;
-            loop {
-                if let Ok(msg) = ui_receiver.recv() {
-                    match msg {
-                        msg::UIMessage::Debug(eip, dat) => {
+            let mut script: VecDeque<String> = match &cli.script {
+                Some(script_path) => std::fs::read_to_string(script_path)
+                    .unwrap()
+                    .lines()
+                    .map(|line| line.to_owned())
+                    .collect(),
+                None => VecDeque::new(),
+            };
+            let mem = unsafe { mem_arc.get().as_mut().unwrap() };
+            let mut quit = cli.script.is_some() && !run_script(&mut script, &mut console, mem);
+
+            let mut last_line = (0, -1);
+            while !quit {
+                match ui_receiver.recv_timeout(Duration::from_millis(10)) {
+                    Ok(msg) => match msg {
+                        msg::UIMessage::Debug(cpu_id, eip, dat) => {
                             let (cur_line, debug_print) =
                                 pdb::render_debug(&debug_data, eip, 2, true);
-                            if cur_line == -1 || cur_line != last_line {
-                                eprintln!("{}\n{}\n", dat, debug_print);
+                            if cur_line == -1 || (cpu_id, cur_line) != last_line {
+                                if cpu_count > 1 {
+                                    eprintln!("CPU {cpu_id}: {}\n{}\n", dat, debug_print);
+                                } else {
+                                    eprintln!("{}\n{}\n", dat, debug_print);
+                                }
                             }
-                            last_line = cur_line;
-                        }
-                        msg::UIMessage::Serial(c) => {
-                            std::io::stdout()
-                                .write_all(std::slice::from_ref(&c))
-                                .unwrap();
-                            std::io::stdout().flush().unwrap();
+                            last_line = (cpu_id, cur_line);
                         }
-                        msg::UIMessage::SetEIP(eip) => {
-                            if record_eips {
-                                *recorded_eips.entry(eip).or_insert(0) += 1;
+                        msg::UIMessage::Serial(c, instructions) => {
+                            if let Some(serial_log) = &mut serial_log {
+                                serial_log.write(c, instructions);
                             }
+
+                            output_translator.output(c, |c| {
+                                std::io::stdout()
+                                    .write_all(std::slice::from_ref(&c))
+                                    .unwrap();
+                            });
+                            std::io::stdout().flush().unwrap();
                         }
                         msg::UIMessage::CPUStarted(_cpu_id) => {
                             cpus_running += 1;
@@ -398,9 +1301,28 @@ fn main() {
                                 break;
                             }
                         }
-                    }
-                } else {
-                    panic!("ui_receiver failed");
+                        msg::UIMessage::Smc(_cpu_id, event) => {
+                            eprintln!("{}\n", event.describe(&debug_data));
+                        }
+                        msg::UIMessage::Log(cpu_id, eip, out) => {
+                            eprintln!("CPU {cpu_id} at {eip:#x}: {out}");
+                        }
+                        msg::UIMessage::Paused(cpu_id, eip, reason) => {
+                            eprintln!("{}", describe_pause(&debug_data, cpu_id, eip, &reason));
+                            console.select_cpu(cpu_id);
+                            // Like the Variables window, but only when something happened
+                            if console.has_variables() {
+                                eprintln!("{}", console.render_variables(mem));
+                            }
+                            quit = !run_script(&mut script, &mut console, mem);
+                        }
+                    },
+                    // Only there so that a file being sent keeps going
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => panic!("ui_receiver failed"),
+                }
+                if let Some(done) = console.pump_transfer() {
+                    eprintln!("{done}");
                 }
             }
         }
@@ -420,7 +1342,31 @@ fn main() {
     }
 
     if let Some(record_path) = cli.record_path {
-        let mut f = BufWriter::new(File::create(record_path).unwrap());
-        serialize_into(&mut f, &recorded_eips).unwrap();
+        let recorded_eips = cpu_states
+            .iter()
+            .map(|state| std::mem::take(&mut *state.samples.lock().unwrap()))
+            .collect();
+        perf::PerfData::new(
+            bin_hash,
+            cpu_states
+                .iter()
+                .map(|state| state.instructions.load(Ordering::Relaxed))
+                .sum(),
+            perf::SamplingMode::Periodic(sample_interval as u32),
+            recorded_eips,
+        )
+        .write(&record_path);
+    }
+
+    if let Some(coverage_path) = cli.coverage {
+        let recorders: Vec<coverage::CoverageRecorder> = cpu_states
+            .iter()
+            .map(|state| state.coverage.lock().unwrap().take().unwrap())
+            .collect();
+        coverage::CoverageData {
+            bin_hash,
+            instructions: coverage::merge(recorders.iter().map(|recorder| recorder.instructions())),
+        }
+        .write(&coverage_path);
     }
 }
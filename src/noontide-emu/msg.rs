@@ -1,8 +1,14 @@
+use noontide_emu::smc::SmcEvent;
+
 pub enum UIMessage {
-    Serial(u8),
-    #[allow(dead_code)]
-    Debug(u64, String),
-    SetEIP(u64),
+    // A byte the program wrote, with the instructions all CPUs had executed by then
+    Serial(u8, u64),
+    Debug(usize, u64, String),
     CPUStarted(usize),
     CPUStopped(usize),
+    Smc(usize, SmcEvent),
+    // A CPU paused at this EIP for the given reason
+    Paused(usize, u64, String),
+    // Output of a logging breakpoint at this EIP
+    Log(usize, u64, String),
 }
@@ -0,0 +1,169 @@
+use tui::{
+    style::{Modifier, Style},
+    text::{Span, Spans, Text},
+};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SearchTarget {
+    Serial,
+    Source,
+    Debug,
+}
+
+// The last search, along with the line of the current match
+pub struct Search {
+    pub pattern: String,
+    pub target: SearchTarget,
+    pub line: Option<usize>,
+}
+
+// Case-insensitive unless the pattern has uppercase letters in it
+fn line_matches(line: &str, pattern: &str) -> bool {
+    if pattern.chars().any(|c| c.is_uppercase()) {
+        line.contains(pattern)
+    } else {
+        line.to_lowercase().contains(pattern)
+    }
+}
+
+impl Search {
+    // Moves to the next match after the current one, or the previous one before it, wrapping
+    // around at the ends. Returns whether anything matched.
+    pub fn advance(&mut self, lines: &[String], forward: bool) -> bool {
+        let len = lines.len();
+        if len == 0 {
+            self.line = None;
+            return false;
+        }
+
+        let start = match (self.line, forward) {
+            (Some(line), true) => line + 1,
+            (Some(line), false) => line + len - 1,
+            (None, true) => 0,
+            (None, false) => len - 1,
+        };
+        self.line = (0..len)
+            .map(|i| {
+                if forward {
+                    (start + i) % len
+                } else {
+                    (start + len - i) % len
+                }
+            })
+            .find(|&i| line_matches(&lines[i], &self.pattern));
+        self.line.is_some()
+    }
+}
+
+// Rows along with the index of the line they belong to, with the rows of the highlighted line
+// shown in reverse video
+pub fn highlight(
+    rows: impl Iterator<Item = (usize, String)>,
+    highlighted: Option<usize>,
+) -> Text<'static> {
+    let lines: Vec<Spans> = rows
+        .map(|(i, line)| {
+            if Some(i) == highlighted {
+                Spans::from(Span::styled(
+                    line,
+                    Style::default().add_modifier(Modifier::REVERSED),
+                ))
+            } else {
+                Spans::from(line)
+            }
+        })
+        .collect();
+    Text::from(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_search(pattern: &str) -> Search {
+        Search {
+            pattern: pattern.to_owned(),
+            target: SearchTarget::Serial,
+            line: None,
+        }
+    }
+
+    fn lines(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|&line| line.to_owned()).collect()
+    }
+
+    #[test]
+    fn next_and_previous_wrap_around() {
+        let lines = lines(&["foo", "bar", "Foo bar", "baz", "foo"]);
+        let mut search = new_search("foo");
+        assert!(search.advance(&lines, true));
+        assert_eq!(search.line, Some(0));
+        assert!(search.advance(&lines, true));
+        assert_eq!(search.line, Some(2));
+        assert!(search.advance(&lines, true));
+        assert_eq!(search.line, Some(4));
+        assert!(search.advance(&lines, true));
+        assert_eq!(search.line, Some(0));
+        assert!(search.advance(&lines, false));
+        assert_eq!(search.line, Some(4));
+        assert!(search.advance(&lines, false));
+        assert_eq!(search.line, Some(2));
+
+        // Starting backwards begins at the end
+        let mut search = new_search("bar");
+        assert!(search.advance(&lines, false));
+        assert_eq!(search.line, Some(2));
+
+        // A single match is found again from itself
+        let mut search = new_search("baz");
+        assert!(search.advance(&lines, true));
+        assert!(search.advance(&lines, true));
+        assert_eq!(search.line, Some(3));
+    }
+
+    #[test]
+    fn case_sensitive_only_with_uppercase() {
+        let lines = lines(&["foo", "Foo"]);
+        let mut search = new_search("Foo");
+        assert!(search.advance(&lines, true));
+        assert!(search.advance(&lines, true));
+        assert_eq!(search.line, Some(1));
+    }
+
+    #[test]
+    fn no_match() {
+        let mut search = new_search("qux");
+        search.line = Some(1);
+        assert!(!search.advance(&lines(&["foo", "bar"]), true));
+        assert_eq!(search.line, None);
+        search.line = Some(0);
+        assert!(!search.advance(&[], false));
+        assert_eq!(search.line, None);
+    }
+
+    #[test]
+    fn highlighted_line() {
+        let rows = [
+            (3, "a".to_owned()),
+            (4, "b".to_owned()),
+            (4, "c".to_owned()),
+        ];
+        let text = highlight(rows.clone().into_iter(), Some(4));
+        let styles: Vec<_> = text
+            .lines
+            .iter()
+            .map(|spans| spans.0[0].style.add_modifier)
+            .collect();
+        assert_eq!(
+            styles,
+            [Modifier::empty(), Modifier::REVERSED, Modifier::REVERSED]
+        );
+        assert_eq!(text.lines[2].0[0].content, "c");
+
+        let text = highlight(rows.into_iter(), None);
+        assert!(text
+            .lines
+            .iter()
+            .all(|spans| spans.0[0].style.add_modifier.is_empty()));
+    }
+}
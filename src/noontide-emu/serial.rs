@@ -1,28 +1,47 @@
 use std::{
     collections::VecDeque,
+    fs::File,
+    io::{BufWriter, Write},
     sync::{
+        atomic::Ordering,
         mpsc::{Receiver, Sender},
         Arc, Barrier,
     },
+    time::Instant,
 };
 
 use bus::BusReader;
+use clap::ValueEnum;
 
-use crate::msg::UIMessage;
+use crate::{cpu::CpuState, msg::UIMessage};
 
 const SERIAL_CONNECTED: usize = 0x13ED27E0;
 const SERIAL_IN: usize = 0x13ED27E8;
 const SERIAL_OUT: usize = 0x13ED27F0;
 
+pub struct SerialOptions {
+    pub input_ending: LineEnding,
+    // Write down every byte given to the program along with the instruction count at the time
+    pub record: Option<BufWriter<File>>,
+    // Bytes to give to the program once this many instructions have run, before anything
+    // that gets typed
+    pub replay: VecDeque<(u64, u8)>,
+    // Only used for the instruction counts
+    pub cpu_states: Vec<Arc<CpuState>>,
+}
+
 pub fn serial_loop(
     mem: &mut [u8],
     io_barrier: Arc<Barrier>,
     ui_sender: Sender<UIMessage>,
-    serial_receiver: Receiver<char>,
+    serial_receiver: Receiver<u8>,
     mut term_rx: BusReader<usize>,
+    mut options: SerialOptions,
 ) {
-    crate::mem::write(mem, SERIAL_CONNECTED, &i64::to_be_bytes(1));
-    let mut input_buffer: VecDeque<char> = VecDeque::new();
+    noontide_emu::mem::write(mem, SERIAL_CONNECTED, &i64::to_be_bytes(1));
+    let mut input_buffer: VecDeque<u8> = VecDeque::new();
+    // Every front-end sends what was typed or read as is, so that they all behave the same
+    let mut input_translator = Translator::new(options.input_ending);
 
     loop {
         io_barrier.wait();
@@ -34,31 +53,381 @@ pub fn serial_loop(
         }
 
         while let Ok(input) = serial_receiver.try_recv() {
-            input_buffer.push_back(input);
+            input_translator.input(input, |c| input_buffer.push_back(c));
         }
 
-        if !input_buffer.is_empty() && crate::mem::read(mem, SERIAL_IN) == 0 {
-            crate::mem::write(
-                mem,
-                SERIAL_IN,
-                &i64::to_be_bytes(input_buffer.pop_front().unwrap() as i64 + 1),
-            );
+        // The CPUs are between cycles, and a cycle ends right after serial is accessed, so
+        // this is the same count whenever the program runs the same way
+        let instructions: u64 = options
+            .cpu_states
+            .iter()
+            .map(|state| state.instructions.load(Ordering::Relaxed))
+            .sum();
+
+        if noontide_emu::mem::read(mem, SERIAL_IN) == 0 {
+            let input = match options.replay.front() {
+                Some(&(at, c)) if at <= instructions => {
+                    options.replay.pop_front();
+                    Some(c)
+                }
+                Some(_) => None,
+                None => input_buffer.pop_front(),
+            };
+
+            if let Some(c) = input {
+                noontide_emu::mem::write(mem, SERIAL_IN, &i64::to_be_bytes(c as i64 + 1));
+                if let Some(record) = &mut options.record {
+                    writeln!(record, "{instructions} {c:#04x}").unwrap();
+                    record.flush().unwrap();
+                }
+            }
         }
 
-        let mut out: u64 = crate::mem::read(mem, SERIAL_OUT) as u64;
+        let mut out: u64 = noontide_emu::mem::read(mem, SERIAL_OUT) as u64;
         if out != 0 {
             out -= 1;
             if out > 255 {
                 eprintln!("Bad serial output: {:#x}", out);
             } else if ui_sender
-                .send(UIMessage::Serial(out.try_into().unwrap()))
+                .send(UIMessage::Serial(out.try_into().unwrap(), instructions))
                 .is_err()
             {
                 break;
             }
 
-            crate::mem::write(mem, SERIAL_OUT, &i64::to_be_bytes(0));
+            noontide_emu::mem::write(mem, SERIAL_OUT, &i64::to_be_bytes(0));
         }
         io_barrier.wait();
     }
 }
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LineEnding {
+    // Bytes are left alone, and LF only moves down a row
+    Raw,
+    Crlf,
+    Lf,
+    Cr,
+}
+
+// Turns line breaks into the ones the other side expects. A line break is CR, LF or CR LF.
+pub struct Translator {
+    ending: LineEnding,
+    after_cr: bool,
+}
+
+impl Translator {
+    pub fn new(ending: LineEnding) -> Self {
+        Translator {
+            ending,
+            after_cr: false,
+        }
+    }
+
+    // Host line breaks into the line ending the program expects
+    pub fn input(&mut self, c: u8, mut out: impl FnMut(u8)) {
+        if self.ending == LineEnding::Raw {
+            return out(c);
+        }
+
+        let after_cr = std::mem::replace(&mut self.after_cr, c == b'\r');
+        match c {
+            // The LF of a CR LF that was already sent
+            b'\n' if after_cr => {}
+            b'\r' | b'\n' => {
+                let ending: &[u8] = match self.ending {
+                    LineEnding::Crlf => b"\r\n",
+                    LineEnding::Lf => b"\n",
+                    LineEnding::Cr => b"\r",
+                    LineEnding::Raw => unreachable!(),
+                };
+                ending.iter().for_each(|&c| out(c));
+            }
+            _ => out(c),
+        }
+    }
+
+    // The program's line endings into LF for display, leaving other CRs alone. A CR is held
+    // back in CRLF mode until it is known whether an LF follows.
+    pub fn output(&mut self, c: u8, mut out: impl FnMut(u8)) {
+        match self.ending {
+            LineEnding::Raw | LineEnding::Lf => out(c),
+            LineEnding::Cr => out(if c == b'\r' { b'\n' } else { c }),
+            LineEnding::Crlf => {
+                if std::mem::replace(&mut self.after_cr, false) {
+                    if c == b'\n' {
+                        return out(c);
+                    }
+                    out(b'\r');
+                }
+
+                if c == b'\r' {
+                    self.after_cr = true;
+                } else {
+                    out(c);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogPrefix {
+    // Seconds since the emulator started
+    Time,
+    // Instructions executed by all CPUs so far
+    Instructions,
+}
+
+// Copy of everything the program sends over serial, written by the UI thread
+pub struct SerialLog {
+    out: BufWriter<File>,
+    prefix: Option<LogPrefix>,
+    start: Instant,
+    at_line_start: bool,
+}
+
+impl SerialLog {
+    pub fn create(path: &str, prefix: Option<LogPrefix>) -> Self {
+        SerialLog {
+            out: BufWriter::new(File::create(path).unwrap()),
+            prefix,
+            start: Instant::now(),
+            at_line_start: true,
+        }
+    }
+
+    pub fn write(&mut self, c: u8, instructions: u64) {
+        if self.at_line_start {
+            match self.prefix {
+                Some(LogPrefix::Time) => {
+                    let elapsed = self.start.elapsed().as_secs_f64();
+                    write!(self.out, "[{elapsed:12.6}] ").unwrap();
+                }
+                Some(LogPrefix::Instructions) => {
+                    write!(self.out, "[{instructions:>15}] ").unwrap();
+                }
+                None => {}
+            }
+        }
+
+        self.out.write_all(&[c]).unwrap();
+        self.at_line_start = c == b'\n';
+
+        // Complete lines can be followed with tail -f
+        if self.at_line_start {
+            self.out.flush().unwrap();
+        }
+    }
+}
+
+// A host file being streamed into serial input with the send command
+pub struct Transfer {
+    path: String,
+    data: Vec<u8>,
+    sent: usize,
+    // Bytes per second
+    rate: u64,
+    start: Instant,
+}
+
+impl Transfer {
+    pub fn open(path: &str, rate: u64) -> Result<Self, String> {
+        Ok(Transfer {
+            path: path.to_owned(),
+            data: std::fs::read(path).map_err(|e| format!("Failed to read {path}: {e}"))?,
+            sent: 0,
+            rate,
+            start: Instant::now(),
+        })
+    }
+
+    // Sends whatever is due by now. Returns whether the whole file has been sent.
+    pub fn pump(&mut self, serial_sender: &Sender<u8>) -> bool {
+        let due = (self.start.elapsed().as_secs_f64() * self.rate as f64) as usize;
+        let due = due.clamp(self.sent, self.data.len());
+        for &c in &self.data[self.sent..due] {
+            serial_sender.send(c).unwrap();
+        }
+        self.sent = due;
+        self.sent == self.data.len()
+    }
+
+    pub fn progress(&self) -> String {
+        let len = self.data.len();
+        let percent = (self.sent * 100).checked_div(len).unwrap_or(100);
+        format!(
+            "Sending {}: {percent}% ({}/{len} bytes)",
+            self.path, self.sent
+        )
+    }
+}
+
+// Reads what --record-input wrote, one "<instructions> <byte>" per line
+pub fn read_input_log(path: &str) -> Result<VecDeque<(u64, u8)>, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("Failed to read {path}: {e}"))?;
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            let parsed = line.split_once(' ').and_then(|(at, c)| {
+                let c = u8::from_str_radix(c.trim().trim_start_matches("0x"), 16).ok()?;
+                Some((at.parse().ok()?, c))
+            });
+            parsed.ok_or_else(|| format!("{path}:{}: Invalid line: {line}", i + 1))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input(ending: LineEnding, data: &[u8]) -> Vec<u8> {
+        let mut translator = Translator::new(ending);
+        let mut ret = Vec::new();
+        data.iter()
+            .for_each(|&c| translator.input(c, |c| ret.push(c)));
+        ret
+    }
+
+    fn output(ending: LineEnding, data: &[u8]) -> Vec<u8> {
+        let mut translator = Translator::new(ending);
+        let mut ret = Vec::new();
+        data.iter()
+            .for_each(|&c| translator.output(c, |c| ret.push(c)));
+        ret
+    }
+
+    #[test]
+    fn input_line_breaks() {
+        let typed = b"a\rb\nc\r\nd\n\re";
+        assert_eq!(input(LineEnding::Raw, typed), typed);
+        assert_eq!(input(LineEnding::Crlf, typed), b"a\r\nb\r\nc\r\nd\r\n\r\ne");
+        assert_eq!(input(LineEnding::Lf, typed), b"a\nb\nc\nd\n\ne");
+        assert_eq!(input(LineEnding::Cr, typed), b"a\rb\rc\rd\r\re");
+    }
+
+    #[test]
+    fn output_line_breaks() {
+        let sent = b"a\r\nb\rc\nd\r";
+        assert_eq!(output(LineEnding::Raw, sent), sent);
+        assert_eq!(output(LineEnding::Lf, sent), sent);
+        assert_eq!(output(LineEnding::Cr, sent), b"a\n\nb\nc\nd\n");
+        // The last CR is held back until the next byte
+        assert_eq!(output(LineEnding::Crlf, sent), b"a\nb\rc\nd");
+        assert_eq!(output(LineEnding::Crlf, b"\r\r\n"), b"\r\n");
+    }
+
+    fn read_log(text: &str) -> Result<Vec<(u64, u8)>, String> {
+        let path = std::env::temp_dir().join(format!("input-{}.log", std::process::id()));
+        let path = path.to_str().unwrap();
+        std::fs::write(path, text).unwrap();
+        let ret = read_input_log(path).map(Vec::from);
+        std::fs::remove_file(path).unwrap();
+        ret.map_err(|e| e.replace(path, "log"))
+    }
+
+    #[test]
+    fn input_log() {
+        assert_eq!(
+            read_log("0 0x61\n\n12 0x0a\r\n  \n12 0xff\n"),
+            Ok(vec![(0, b'a'), (12, b'\n'), (12, 0xff)])
+        );
+        assert_eq!(read_log(""), Ok(vec![]));
+        assert_eq!(
+            read_log("5 0x61\nx 0x62"),
+            Err("log:2: Invalid line: x 0x62".to_owned())
+        );
+        assert!(read_log("5").is_err());
+        assert!(read_log("5 0x100").is_err());
+        assert!(read_log("-1 0x61").is_err());
+        assert!(read_input_log("/nonexistent/input.log").is_err());
+    }
+
+    // Writes data through a SerialLog, one instruction count per byte, and reads the file back
+    fn log(prefix: Option<LogPrefix>, data: &[u8]) -> Vec<u8> {
+        let path = std::env::temp_dir().join(format!("serial-{}.log", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut log = SerialLog::create(path, prefix);
+        for (i, &c) in data.iter().enumerate() {
+            log.write(c, 100 * i as u64);
+        }
+
+        // Complete lines are already there
+        let flushed = std::fs::read(path).unwrap();
+        drop(log);
+        let ret = std::fs::read(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        let lines_end = ret.iter().rposition(|&c| c == b'\n').map_or(0, |i| i + 1);
+        assert_eq!(flushed, ret[..lines_end]);
+        ret
+    }
+
+    #[test]
+    fn serial_log() {
+        assert_eq!(log(None, b"ab\ncd\n\nef"), b"ab\ncd\n\nef");
+        assert_eq!(
+            log(Some(LogPrefix::Instructions), b"ab\nc\r\n\xff"),
+            b"[              0] ab\n[            300] c\r\n[            600] \xff"
+        );
+
+        let timed = String::from_utf8(log(Some(LogPrefix::Time), b"a\nb")).unwrap();
+        let lines: Vec<&str> = timed.lines().collect();
+        assert_eq!(lines.len(), 2);
+        for (line, text) in lines.iter().zip(["a", "b"]) {
+            let (time, rest) = line.split_once("] ").unwrap();
+            assert_eq!(rest, text);
+            assert!(
+                time.strip_prefix('[')
+                    .unwrap()
+                    .trim()
+                    .parse::<f64>()
+                    .unwrap()
+                    < 10.0
+            );
+        }
+    }
+
+    fn transfer(data: &[u8], rate: u64, started_ms_ago: u64) -> Transfer {
+        Transfer {
+            path: "file".to_owned(),
+            data: data.to_vec(),
+            sent: 0,
+            rate,
+            start: Instant::now() - std::time::Duration::from_millis(started_ms_ago),
+        }
+    }
+
+    #[test]
+    fn transfer_pacing() {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let mut transfer = transfer(b"0123456789abcdefghij", 10, 550);
+        assert!(!transfer.pump(&sender));
+        assert_eq!(receiver.try_iter().collect::<Vec<_>>(), b"01234");
+        assert_eq!(transfer.progress(), "Sending file: 25% (5/20 bytes)");
+
+        // Nothing is sent twice, and the rest is sent once it is due
+        transfer.start -= std::time::Duration::from_secs(10);
+        assert!(transfer.pump(&sender));
+        assert_eq!(receiver.try_iter().collect::<Vec<_>>(), b"56789abcdefghij");
+        assert_eq!(transfer.progress(), "Sending file: 100% (20/20 bytes)");
+        assert!(transfer.pump(&sender));
+        assert_eq!(receiver.try_iter().count(), 0);
+    }
+
+    #[test]
+    fn transfer_completion() {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let mut empty = transfer(b"", 10, 0);
+        assert!(empty.pump(&sender));
+        assert_eq!(empty.progress(), "Sending file: 100% (0/0 bytes)");
+
+        // Not a single byte is due yet
+        let mut slow = transfer(b"abc", 1, 0);
+        assert!(!slow.pump(&sender));
+        assert_eq!(receiver.try_iter().count(), 0);
+
+        assert!(Transfer::open("/nonexistent/file", 10).is_err());
+    }
+}
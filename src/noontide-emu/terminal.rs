@@ -0,0 +1,653 @@
+use std::collections::VecDeque;
+
+use tui::{
+    style::{Color, Modifier, Style},
+    text::{Span, Spans, Text},
+};
+
+// Rows kept above the screen, the oldest ones get dropped first
+const SCROLLBACK_ROWS: usize = 10000;
+
+#[derive(Clone, Copy, PartialEq)]
+struct Cell {
+    c: char,
+    style: Style,
+}
+
+const BLANK: Cell = Cell {
+    c: ' ',
+    style: Style {
+        fg: None,
+        bg: None,
+        add_modifier: Modifier::empty(),
+        sub_modifier: Modifier::empty(),
+    },
+};
+
+enum State {
+    Ground,
+    // After ESC
+    Escape,
+    // After ESC ( or ESC ), which only pick a character set
+    Charset,
+    // After ESC [, with the parameter bytes collected so far
+    Csi(String),
+}
+
+// A VT100 subset behind the Serial pane: printing with automatic wrapping, CR, LF, BS, TAB,
+// cursor movement, erasing, SGR colours and scroll regions. Rows scrolled off the top of the
+// screen go into a bounded scrollback. LF also returns the carriage unless the serial output is left raw.
+pub struct Terminal {
+    width: usize,
+    height: usize,
+    scrollback: VecDeque<Vec<Cell>>,
+    screen: Vec<Vec<Cell>>,
+    row: usize,
+    col: usize,
+    // The last column was written, the next character goes on the next row
+    wrap_pending: bool,
+    style: Style,
+    saved_cursor: (usize, usize),
+    // First and last row of the scroll region set with ESC [ r
+    scroll_top: usize,
+    scroll_bottom: usize,
+    state: State,
+    lf_newline: bool,
+}
+
+fn basic_color(i: u16, bright: bool) -> Color {
+    match (i, bright) {
+        (0, false) => Color::Black,
+        (1, false) => Color::Red,
+        (2, false) => Color::Green,
+        (3, false) => Color::Yellow,
+        (4, false) => Color::Blue,
+        (5, false) => Color::Magenta,
+        (6, false) => Color::Cyan,
+        (7, false) => Color::Gray,
+        (0, true) => Color::DarkGray,
+        (1, true) => Color::LightRed,
+        (2, true) => Color::LightGreen,
+        (3, true) => Color::LightYellow,
+        (4, true) => Color::LightBlue,
+        (5, true) => Color::LightMagenta,
+        (6, true) => Color::LightCyan,
+        _ => Color::White,
+    }
+}
+
+// 38;5;n and 38;2;r;g;b, returning the colour and how many more parameters it took
+fn extended_color(params: &[u16]) -> Option<(Color, usize)> {
+    match params {
+        [5, n, ..] => Some((Color::Indexed(*n as u8), 2)),
+        [2, r, g, b, ..] => Some((Color::Rgb(*r as u8, *g as u8, *b as u8), 4)),
+        _ => None,
+    }
+}
+
+impl Terminal {
+    pub fn new(width: usize, height: usize, lf_newline: bool) -> Self {
+        let width = width.max(1);
+        let height = height.max(1);
+        Terminal {
+            width,
+            height,
+            scrollback: VecDeque::new(),
+            screen: vec![vec![BLANK; width]; height],
+            row: 0,
+            col: 0,
+            wrap_pending: false,
+            style: Style::default(),
+            saved_cursor: (0, 0),
+            scroll_top: 0,
+            scroll_bottom: height - 1,
+            state: State::Ground,
+            lf_newline,
+        }
+    }
+
+    // Rows in the scrollback and on the screen
+    pub fn rows(&self) -> usize {
+        self.scrollback.len() + self.height
+    }
+
+    // Rows that scrolled off the top of the screen
+    pub fn history(&self) -> usize {
+        self.scrollback.len()
+    }
+
+    fn row_at(&self, i: usize) -> &[Cell] {
+        if i < self.scrollback.len() {
+            &self.scrollback[i]
+        } else {
+            &self.screen[i - self.scrollback.len()]
+        }
+    }
+
+    // The text of every row, for searching
+    pub fn row_texts(&self) -> Vec<String> {
+        (0..self.rows())
+            .map(|i| {
+                let text: String = self.row_at(i).iter().map(|cell| cell.c).collect();
+                text.trim_end().to_owned()
+            })
+            .collect()
+    }
+
+    // Follows the size of the Serial pane, keeping the bottom of the screen
+    pub fn resize(&mut self, width: usize, height: usize) {
+        let width = width.max(1);
+        let height = height.max(1);
+        if (width, height) == (self.width, self.height) {
+            return;
+        }
+
+        for row in &mut self.screen {
+            row.resize(width, BLANK);
+        }
+        // Rows below the cursor are dropped first, then the ones above it go into the
+        // scrollback
+        while self.screen.len() > height && self.row + 1 < self.screen.len() {
+            self.screen.pop();
+        }
+        while self.screen.len() > height {
+            let row = self.screen.remove(0);
+            self.push_scrollback(row);
+            self.row -= 1;
+        }
+        self.screen.resize(height, vec![BLANK; width]);
+
+        self.width = width;
+        self.height = height;
+        self.scroll_top = 0;
+        self.scroll_bottom = height - 1;
+        self.col = std::cmp::min(self.col, width - 1);
+        self.wrap_pending = false;
+        self.saved_cursor = (
+            std::cmp::min(self.saved_cursor.0, height - 1),
+            std::cmp::min(self.saved_cursor.1, width - 1),
+        );
+    }
+
+    fn restore_cursor(&mut self) {
+        self.row = std::cmp::min(self.saved_cursor.0, self.height - 1);
+        self.col = std::cmp::min(self.saved_cursor.1, self.width - 1);
+        self.wrap_pending = false;
+    }
+
+    // Returns whether the oldest row was dropped
+    fn push_scrollback(&mut self, row: Vec<Cell>) -> bool {
+        self.scrollback.push_back(row);
+        let dropped = self.scrollback.len() > SCROLLBACK_ROWS;
+        if dropped {
+            self.scrollback.pop_front();
+        }
+        dropped
+    }
+
+    // Moves down a row, scrolling the scroll region at its bottom. Returns whether a row was
+    // scrolled into the scrollback, and whether the oldest one was dropped for it.
+    fn line_feed(&mut self) -> (bool, bool) {
+        if self.row != self.scroll_bottom {
+            self.row = std::cmp::min(self.row + 1, self.height - 1);
+            return (false, false);
+        }
+
+        let row = self.screen.remove(self.scroll_top);
+        self.screen
+            .insert(self.scroll_bottom, vec![BLANK; self.width]);
+        // Only rows leaving the top of the screen are kept
+        if self.scroll_top != 0 {
+            return (false, false);
+        }
+        (true, self.push_scrollback(row))
+    }
+
+    // Erased cells keep the background colour
+    fn erase(&mut self, row: usize, cols: std::ops::Range<usize>) {
+        let blank = Cell {
+            c: ' ',
+            style: Style {
+                bg: self.style.bg,
+                ..BLANK.style
+            },
+        };
+        self.screen[row][cols].fill(blank);
+    }
+
+    fn set_style(&mut self, params: &[u16]) {
+        if params.is_empty() {
+            self.style = Style::default();
+            return;
+        }
+
+        let mut i = 0;
+        while i < params.len() {
+            match params[i] {
+                0 => self.style = Style::default(),
+                1 => self.style = self.style.add_modifier(Modifier::BOLD),
+                2 => self.style = self.style.add_modifier(Modifier::DIM),
+                3 => self.style = self.style.add_modifier(Modifier::ITALIC),
+                4 => self.style = self.style.add_modifier(Modifier::UNDERLINED),
+                5 => self.style = self.style.add_modifier(Modifier::SLOW_BLINK),
+                7 => self.style = self.style.add_modifier(Modifier::REVERSED),
+                22 => self
+                    .style
+                    .add_modifier
+                    .remove(Modifier::BOLD | Modifier::DIM),
+                23 => self.style.add_modifier.remove(Modifier::ITALIC),
+                24 => self.style.add_modifier.remove(Modifier::UNDERLINED),
+                25 => self.style.add_modifier.remove(Modifier::SLOW_BLINK),
+                27 => self.style.add_modifier.remove(Modifier::REVERSED),
+                n @ 30..=37 => self.style.fg = Some(basic_color(n - 30, false)),
+                n @ 90..=97 => self.style.fg = Some(basic_color(n - 90, true)),
+                39 => self.style.fg = None,
+                n @ 40..=47 => self.style.bg = Some(basic_color(n - 40, false)),
+                n @ 100..=107 => self.style.bg = Some(basic_color(n - 100, true)),
+                49 => self.style.bg = None,
+                n @ (38 | 48) => {
+                    // What follows an incomplete one can't be told apart from other parameters
+                    let Some((color, taken)) = extended_color(&params[i + 1..]) else {
+                        break;
+                    };
+                    if n == 38 {
+                        self.style.fg = Some(color);
+                    } else {
+                        self.style.bg = Some(color);
+                    }
+                    i += taken;
+                }
+                _ => {}
+            }
+            i += 1;
+        }
+    }
+
+    fn csi(&mut self, params: &str, command: char) {
+        // Private sequences like ESC [ ? 25 h are only about how the cursor looks
+        if params.starts_with('?') {
+            return;
+        }
+
+        let params: Vec<u16> = params
+            .split(';')
+            .map(|param| param.parse().unwrap_or(0))
+            .collect();
+        let params = if params == [0] && command == 'm' {
+            &[][..]
+        } else {
+            &params[..]
+        };
+        // Movement counts of 0 mean 1
+        let count = params.first().map_or(1, |&n| n.max(1) as usize);
+
+        self.wrap_pending = false;
+        match command {
+            'A' => self.row = self.row.saturating_sub(count),
+            'B' => self.row = std::cmp::min(self.row + count, self.height - 1),
+            'C' => self.col = std::cmp::min(self.col + count, self.width - 1),
+            'D' => self.col = self.col.saturating_sub(count),
+            'E' => {
+                self.row = std::cmp::min(self.row + count, self.height - 1);
+                self.col = 0;
+            }
+            'F' => {
+                self.row = self.row.saturating_sub(count);
+                self.col = 0;
+            }
+            'G' => self.col = std::cmp::min(count - 1, self.width - 1),
+            'd' => self.row = std::cmp::min(count - 1, self.height - 1),
+            'H' | 'f' => {
+                let row = params.first().map_or(1, |&n| n.max(1) as usize);
+                let col = params.get(1).map_or(1, |&n| n.max(1) as usize);
+                self.row = std::cmp::min(row - 1, self.height - 1);
+                self.col = std::cmp::min(col - 1, self.width - 1);
+            }
+            'J' => {
+                let (row, col, width, height) = (self.row, self.col, self.width, self.height);
+                match params.first().copied().unwrap_or(0) {
+                    0 => {
+                        self.erase(row, col..width);
+                        for i in row + 1..height {
+                            self.erase(i, 0..width);
+                        }
+                    }
+                    1 => {
+                        for i in 0..row {
+                            self.erase(i, 0..width);
+                        }
+                        self.erase(row, 0..col + 1);
+                    }
+                    _ => {
+                        for i in 0..height {
+                            self.erase(i, 0..width);
+                        }
+                    }
+                }
+            }
+            'K' => {
+                let (row, col, width) = (self.row, self.col, self.width);
+                match params.first().copied().unwrap_or(0) {
+                    0 => self.erase(row, col..width),
+                    1 => self.erase(row, 0..col + 1),
+                    _ => self.erase(row, 0..width),
+                }
+            }
+            'm' => self.set_style(params),
+            'r' => {
+                let top = count - 1;
+                let bottom = match params.get(1) {
+                    Some(&n) if n > 0 => std::cmp::min(n as usize, self.height),
+                    _ => self.height,
+                } - 1;
+                if top < bottom {
+                    (self.scroll_top, self.scroll_bottom) = (top, bottom);
+                }
+                (self.row, self.col) = (0, 0);
+            }
+            's' => self.saved_cursor = (self.row, self.col),
+            'u' => self.restore_cursor(),
+            _ => {}
+        }
+    }
+
+    fn print(&mut self, c: char) -> (bool, bool) {
+        let mut ret = (false, false);
+        if self.wrap_pending {
+            self.wrap_pending = false;
+            self.col = 0;
+            ret = self.line_feed();
+        }
+
+        self.screen[self.row][self.col] = Cell {
+            c,
+            style: self.style,
+        };
+        if self.col + 1 == self.width {
+            self.wrap_pending = true;
+        } else {
+            self.col += 1;
+        }
+        ret
+    }
+
+    // Feeds a byte the program sent. Returns whether a row was scrolled into the scrollback,
+    // and whether the oldest one was dropped for it.
+    pub fn push(&mut self, c: u8) -> (bool, bool) {
+        match std::mem::replace(&mut self.state, State::Ground) {
+            State::Ground => match c {
+                0x1B => self.state = State::Escape,
+                b'\r' => {
+                    self.col = 0;
+                    self.wrap_pending = false;
+                }
+                b'\n' => {
+                    if self.lf_newline {
+                        self.col = 0;
+                    }
+                    self.wrap_pending = false;
+                    return self.line_feed();
+                }
+                0x08 => {
+                    self.col = self.col.saturating_sub(1);
+                    self.wrap_pending = false;
+                }
+                b'\t' => self.col = std::cmp::min((self.col / 8 + 1) * 8, self.width - 1),
+                0x20..=0x7E => return self.print(c as char),
+                // Characters beyond ASCII are shown as Latin-1
+                0xA0..=0xFF => return self.print(c as char),
+                _ => {}
+            },
+            State::Escape => match c {
+                b'[' => self.state = State::Csi(String::new()),
+                b'7' => self.saved_cursor = (self.row, self.col),
+                b'8' => self.restore_cursor(),
+                b'(' | b')' => self.state = State::Charset,
+                b'D' => return self.line_feed(),
+                b'E' => {
+                    self.col = 0;
+                    return self.line_feed();
+                }
+                b'M' => {
+                    if self.row == self.scroll_top {
+                        self.screen.remove(self.scroll_bottom);
+                        self.screen.insert(self.scroll_top, vec![BLANK; self.width]);
+                    } else {
+                        self.row = self.row.saturating_sub(1);
+                    }
+                }
+                b'c' => {
+                    self.style = Style::default();
+                    for i in 0..self.height {
+                        self.erase(i, 0..self.width);
+                    }
+                    (self.row, self.col) = (0, 0);
+                    (self.scroll_top, self.scroll_bottom) = (0, self.height - 1);
+                }
+                _ => {}
+            },
+            State::Charset => {}
+            State::Csi(mut params) => match c {
+                0x40..=0x7E => self.csi(&params, c as char),
+                // Bail out of sequences that never end
+                _ if params.len() > 32 => {}
+                _ => {
+                    params.push(c as char);
+                    self.state = State::Csi(params);
+                }
+            },
+        }
+
+        (false, false)
+    }
+
+    // height rows starting with row top, with the rows of the highlighted one and the cursor
+    // shown in reverse video
+    pub fn render(&self, top: usize, height: usize, highlighted: Option<usize>) -> Text<'static> {
+        let cursor = (self.scrollback.len() + self.row, self.col);
+        let end = std::cmp::min(top + height, self.rows());
+        let lines: Vec<Spans> = (top..end)
+            .map(|i| {
+                let mut spans = Vec::new();
+                let mut text = String::new();
+                let mut style = None;
+                for (col, cell) in self.row_at(i).iter().enumerate() {
+                    let mut cell_style = cell.style;
+                    if Some(i) == highlighted || (i, col) == cursor {
+                        cell_style = cell_style.add_modifier(Modifier::REVERSED);
+                    }
+
+                    if style != Some(cell_style) {
+                        if let Some(style) = style {
+                            spans.push(Span::styled(std::mem::take(&mut text), style));
+                        }
+                        style = Some(cell_style);
+                    }
+                    text.push(cell.c);
+                }
+                if let Some(style) = style {
+                    spans.push(Span::styled(text, style));
+                }
+                Spans::from(spans)
+            })
+            .collect();
+        Text::from(lines)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed(terminal: &mut Terminal, data: &[u8]) {
+        for &c in data {
+            terminal.push(c);
+        }
+    }
+
+    #[test]
+    fn restore_after_shrinking() {
+        let mut terminal = Terminal::new(20, 10, true);
+        feed(&mut terminal, b"\x1b[10;15H\x1b7");
+        terminal.resize(10, 4);
+        feed(&mut terminal, b"\x1b8x\x1b[9;18H\x1b[s");
+        terminal.resize(5, 2);
+        feed(&mut terminal, b"\x1b[uy");
+        assert_eq!(terminal.row_texts()[terminal.history()..], ["", "    y"]);
+    }
+
+    #[test]
+    fn charset_designators() {
+        let mut terminal = Terminal::new(20, 2, true);
+        feed(&mut terminal, b"\x1b(Bab\x1b)0c\x1b[mB");
+        assert_eq!(terminal.row_texts()[0], "abcB");
+    }
+
+    fn screen_texts(terminal: &Terminal) -> Vec<String> {
+        terminal.row_texts()[terminal.history()..].to_vec()
+    }
+
+    #[test]
+    fn cursor_movement() {
+        let mut terminal = Terminal::new(10, 5, true);
+        feed(&mut terminal, b"\x1b[3;4Ha\x1b[Ab\x1b[2Bc\x1b[0Dd\x1b[9Ce");
+        assert_eq!(
+            screen_texts(&terminal),
+            ["", "    b", "   a", "     d   e", ""]
+        );
+
+        // Moves are clamped to the screen
+        feed(&mut terminal, b"\x1b[99;99Hf\x1b[99Ag\x1b[99Dh");
+        assert_eq!((terminal.row, terminal.col), (0, 1));
+        assert_eq!(terminal.row_texts()[0], "h        g");
+        assert_eq!(terminal.row_texts()[4], "         f");
+
+        let mut terminal = Terminal::new(10, 5, true);
+        feed(
+            &mut terminal,
+            b"\x1b[2Ea\x1b[Fb\x1b[5Gc\x1b[3dd\x1b[Hx\tt\x08u\rv",
+        );
+        assert_eq!(
+            screen_texts(&terminal),
+            ["v       u", "b   c", "a    d", "", ""]
+        );
+    }
+
+    #[test]
+    fn wrapping_and_line_feeds() {
+        let mut terminal = Terminal::new(4, 3, false);
+        // The cursor stays on the last column until something else is printed
+        feed(&mut terminal, b"abcd\r\nefghi\nj");
+        assert_eq!(screen_texts(&terminal), ["efgh", "i", " j"]);
+        assert_eq!(terminal.row_texts()[..terminal.history()], ["abcd"]);
+    }
+
+    #[test]
+    fn erasing() {
+        let lines = b"\x1b[Haaaa\r\nbbbb\r\ncccc\x1b[2;2H";
+        let erased = |seq: &[u8]| {
+            let mut terminal = Terminal::new(4, 3, true);
+            feed(&mut terminal, lines);
+            feed(&mut terminal, seq);
+            screen_texts(&terminal)
+        };
+        assert_eq!(erased(b"\x1b[J"), ["aaaa", "b", ""]);
+        assert_eq!(erased(b"\x1b[1J"), ["", "  bb", "cccc"]);
+        assert_eq!(erased(b"\x1b[2J"), ["", "", ""]);
+        assert_eq!(erased(b"\x1b[K"), ["aaaa", "b", "cccc"]);
+        assert_eq!(erased(b"\x1b[1K"), ["aaaa", "  bb", "cccc"]);
+        assert_eq!(erased(b"\x1b[2K"), ["aaaa", "", "cccc"]);
+
+        // The background colour stays
+        let mut terminal = Terminal::new(4, 1, true);
+        feed(&mut terminal, b"ab\x1b[1;44m\x1b[1K");
+        assert_eq!(
+            terminal.screen[0][0].style,
+            Style::default().bg(Color::Blue)
+        );
+        assert_eq!(
+            terminal.screen[0][2].style,
+            Style::default().bg(Color::Blue)
+        );
+        assert_eq!(terminal.screen[0][3].style, Style::default());
+    }
+
+    #[test]
+    fn sgr() {
+        let mut terminal = Terminal::new(10, 1, true);
+        feed(
+            &mut terminal,
+            b"\x1b[1;31ma\x1b[22;4;92;48;5;200mb\x1b[39;24;48;2;1;2;3mc\x1b[0md\x1b[7me\x1b[mf\x1b[38;5mg",
+        );
+        let styles: Vec<Style> = terminal.screen[0][..7]
+            .iter()
+            .map(|cell| cell.style)
+            .collect();
+        assert_eq!(
+            styles,
+            [
+                Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
+                Style::default()
+                    .fg(Color::LightGreen)
+                    .bg(Color::Indexed(200))
+                    .add_modifier(Modifier::UNDERLINED),
+                Style::default().bg(Color::Rgb(1, 2, 3)),
+                Style::default(),
+                Style::default().add_modifier(Modifier::REVERSED),
+                Style::default(),
+                // An incomplete extended colour is ignored
+                Style::default(),
+            ]
+        );
+    }
+
+    #[test]
+    fn scroll_regions() {
+        let mut terminal = Terminal::new(4, 4, true);
+        feed(&mut terminal, b"a\nb\nc\nd\x1b[2;3r");
+        assert_eq!((terminal.row, terminal.col), (0, 0));
+
+        // Line feeds at the bottom of the region only scroll the region, into nowhere
+        feed(&mut terminal, b"\x1b[3;1H\n\ne\x1b[4;1H\n");
+        assert_eq!(terminal.history(), 0);
+        assert_eq!(screen_texts(&terminal), ["a", "", "e", "d"]);
+
+        // Reverse index at the top of the region scrolls it down
+        feed(&mut terminal, b"\x1b[2;1Hf\x1bMg");
+        assert_eq!(screen_texts(&terminal), ["a", " g", "f", "d"]);
+
+        // Invalid regions are ignored, and resetting scrolls the whole screen again
+        feed(&mut terminal, b"\x1b[3;3r\x1b[4;1H\n");
+        assert_eq!(terminal.history(), 0);
+        feed(&mut terminal, b"\x1b[r\x1b[4;1H\n");
+        assert_eq!(terminal.history(), 1);
+        assert_eq!(terminal.row_texts(), ["a", " g", "f", "d", ""]);
+    }
+
+    #[test]
+    fn scrollback() {
+        let mut terminal = Terminal::new(8, 2, true);
+        let mut dropped = 0;
+        for i in 0..SCROLLBACK_ROWS + 5 {
+            for c in format!("{i}\n").bytes() {
+                let (scrolled, dropped_row) = terminal.push(c);
+                assert_eq!(scrolled, i > 0 && c == b'\n');
+                dropped += dropped_row as usize;
+            }
+        }
+        assert_eq!(dropped, 4);
+        assert_eq!(terminal.history(), SCROLLBACK_ROWS);
+        assert_eq!(terminal.rows(), SCROLLBACK_ROWS + 2);
+        let texts = terminal.row_texts();
+        assert_eq!(texts[0], "4");
+        assert_eq!(texts[SCROLLBACK_ROWS], format!("{}", SCROLLBACK_ROWS + 4));
+        assert_eq!(texts[SCROLLBACK_ROWS + 1], "");
+
+        // Shrinking pushes the rows above the cursor into the scrollback
+        let mut terminal = Terminal::new(8, 4, true);
+        feed(&mut terminal, b"a\nb\nc");
+        terminal.resize(8, 2);
+        assert_eq!(terminal.row_texts(), ["a", "b", "c"]);
+        assert_eq!(terminal.history(), 1);
+    }
+}
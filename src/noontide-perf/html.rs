@@ -0,0 +1,211 @@
+use std::fmt::Write;
+
+use noontide_emu::{pdb, perf};
+
+use crate::percentage;
+
+const TOP_REGIONS: usize = 20;
+
+const STYLE: &str = "
+body { font-family: sans-serif; margin: 1em; }
+table.regions { border-collapse: collapse; margin-bottom: 1em; }
+table.regions td, table.regions th { border: 1px solid #ccc; padding: 2px 8px; }
+table.regions td.num { text-align: right; font-family: monospace; }
+.listing { font-family: monospace; }
+.line { display: flex; }
+.gutter { width: 6em; flex-shrink: 0; text-align: right; padding-right: 1em; }
+.text { flex-grow: 1; white-space: pre; }
+details { margin-left: 1em; border-left: 1px solid #ccc; }
+summary { cursor: pointer; }
+";
+
+fn escape(inp: &str) -> String {
+    let mut ret = String::with_capacity(inp.len());
+    for c in inp.chars() {
+        match c {
+            '&' => ret.push_str("&amp;"),
+            '<' => ret.push_str("&lt;"),
+            '>' => ret.push_str("&gt;"),
+            '"' => ret.push_str("&quot;"),
+            _ => ret.push(c),
+        }
+    }
+
+    ret
+}
+
+// Red background whose intensity scales with the share of the hottest line
+fn heat_style(percentage: f64, max_percentage: f64) -> String {
+    if percentage == 0.0 || max_percentage == 0.0 {
+        return "".to_owned();
+    }
+
+    let alpha = 0.1 + 0.9 * (percentage / max_percentage);
+    format!(" style=\"background: rgba(255, 0, 0, {alpha:.3})\"")
+}
+
+fn push_line(ret: &mut String, i: usize, text: &str, percentage: f64, max_percentage: f64) {
+    let percentage_str = if percentage == 0.0 {
+        "".to_owned()
+    } else {
+        format!("{percentage:.2}")
+    };
+
+    writeln!(
+        ret,
+        "<div class=\"line\" id=\"L{0}\"><span class=\"gutter\"{1}>{2}</span><span class=\"text\">{3}</span></div>",
+        i + 1,
+        heat_style(percentage, max_percentage),
+        percentage_str,
+        escape(text)
+    )
+    .unwrap();
+}
+
+// Renders a single page report containing a top regions table and the annotated source
+// listing, MSQ regions at or below msq_depth start out collapsed
+pub fn render_report(
+    title: &str,
+    perf_data: &perf::PerfData,
+    debug_data: &pdb::DebugData,
+    msq_depth: usize,
+) -> String {
+    let recorded_eips = perf_data.merged_hits();
+    let total_hits: u64 = recorded_eips.iter().map(|record| record.1).sum();
+    let line_percentages: Vec<f64> = perf::hits_per_line(debug_data, &recorded_eips)
+        .into_iter()
+        .map(|hits| percentage(hits, total_hits))
+        .collect();
+    let max_percentage = line_percentages.iter().cloned().fold(0.0, f64::max);
+
+    let regions = pdb::find_msq_regions(debug_data);
+    let region_percentages: Vec<f64> = perf::hits_per_region(debug_data, &regions, &recorded_eips)
+        .into_iter()
+        .map(|hits| percentage(hits, total_hits))
+        .collect();
+
+    let mut ret = String::new();
+    writeln!(
+        ret,
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{0}</title>\n<style>{STYLE}</style>\n</head>\n<body>\n<h1>{0}</h1>",
+        escape(title)
+    )
+    .unwrap();
+    writeln!(
+        ret,
+        "<p>CPUs: {}, instructions: {}, sampling: {:?}, samples: {}</p>",
+        perf_data.cpu_count, perf_data.total_instructions, perf_data.sampling_mode, total_hits
+    )
+    .unwrap();
+
+    if !regions.is_empty() {
+        let mut top_regions: Vec<usize> = (0..regions.len()).collect();
+        top_regions.sort_by(|a, b| region_percentages[*b].total_cmp(&region_percentages[*a]));
+
+        ret.push_str("<h2>Top regions</h2>\n<table class=\"regions\">\n");
+        ret.push_str("<tr><th>%</th><th>Depth</th><th>Region</th></tr>\n");
+        for i in top_regions.into_iter().take(TOP_REGIONS) {
+            if region_percentages[i] == 0.0 {
+                break;
+            }
+
+            writeln!(
+                ret,
+                "<tr><td class=\"num\">{:.2}</td><td class=\"num\">{}</td><td><a href=\"#L{}\">{}</a></td></tr>",
+                region_percentages[i],
+                regions[i].depth,
+                regions[i].start + 1,
+                escape(&regions[i].name)
+            )
+            .unwrap();
+        }
+        ret.push_str("</table>\n");
+    }
+
+    ret.push_str("<h2>Source</h2>\n<div class=\"listing\">\n");
+    let mut region_it = regions.iter().zip(&region_percentages).peekable();
+    let mut region_ends: Vec<usize> = Vec::new();
+    for (i, (_offset, text)) in debug_data.offsets.iter().enumerate() {
+        while let Some((region, region_percentage)) = region_it.next_if(|(r, _)| r.start == i) {
+            let open = if region.depth < msq_depth {
+                " open"
+            } else {
+                ""
+            };
+            writeln!(
+                ret,
+                "<details{open}><summary>{:.2}% {}</summary>",
+                region_percentage,
+                escape(&region.name)
+            )
+            .unwrap();
+            region_ends.push(region.end);
+        }
+
+        push_line(&mut ret, i, text, line_percentages[i], max_percentage);
+
+        while region_ends.last() == Some(&i) {
+            ret.push_str("</details>\n");
+            region_ends.pop();
+        }
+    }
+    ret.push_str("</div>\n</body>\n</html>\n");
+
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn escaping() {
+        assert_eq!(escape("plain"), "plain");
+        assert_eq!(
+            escape("<a href=\"x\">&amp;</a>"),
+            "&lt;a href=&quot;x&quot;&gt;&amp;amp;&lt;/a&gt;"
+        );
+    }
+
+    #[test]
+    fn report_is_escaped() {
+        let lines = [
+            "rem MSQ_START 0 <outer> & \"co\"",
+            "a < b",
+            "rem MSQ_START 1 inner",
+            "c > d",
+            "rem MSQ_END 1",
+            "rem MSQ_END 0",
+        ];
+        let debug_data = pdb::DebugData {
+            offsets: lines
+                .iter()
+                .enumerate()
+                .map(|(i, &line)| (24 * i as u64, line.to_owned()))
+                .collect(),
+        };
+        let perf_data = perf::PerfData {
+            bin_hash: None,
+            cpu_count: 1,
+            total_instructions: 4,
+            sampling_mode: perf::SamplingMode::Periodic(1),
+            cpus: vec![HashMap::from([(24, 3), (72, 1)])],
+        };
+        let page = render_report("<title>", &perf_data, &debug_data, 1);
+
+        assert!(page.contains("<title>&lt;title&gt;</title>"));
+        assert!(page.contains("<span class=\"text\">a &lt; b</span>"));
+        assert!(page.contains("<span class=\"text\">c &gt; d</span>"));
+        assert!(page.contains("<summary>100.00% &lt;outer&gt; &amp; &quot;co&quot;</summary>"));
+        assert!(!page.contains("<outer>"));
+
+        // Regions from msq_depth on start out collapsed, and all of them are closed
+        assert!(page.contains("<details open><summary>100.00% &lt;outer"));
+        assert!(page.contains("<details><summary>25.00% inner</summary>"));
+        assert_eq!(page.matches("<details").count(), 2);
+        assert_eq!(page.matches("</details>").count(), 2);
+        assert!(page.contains("id=\"L2\"><span class=\"gutter\" style=\"background: rgba(255, 0, 0, 1.000)\">75.00</span>"));
+    }
+}
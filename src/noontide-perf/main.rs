@@ -1,62 +1,214 @@
-use std::fs::File;
-
-use bincode::deserialize_from;
-use clap::Parser;
-use colored::Colorize;
 use std::collections::HashMap;
 
-use noontide_emu::pdb;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use colored::{ColoredString, Colorize};
+use serde::Serialize;
+
+use noontide_emu::{loader, pdb, perf};
+
+mod html;
 
 #[derive(Parser)]
 #[command(name = "noontide-perf")]
 #[command(author = "NyanCatTW1")]
 #[command(about = "Generate and display report from a .perf file", long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    report: ReportArgs,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[command(about = "Compare two .perf files of the same program")]
+    Diff(DiffArgs),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    Text,
+    Json,
+    Csv,
+}
+
+#[derive(Args)]
+struct ReportArgs {
+    // Only optional for clap's sake, both are required unless diff is used
+    #[arg(required = true)]
     #[arg(help = "Path to the .perf file")]
-    perf_path: String,
+    perf_path: Option<String>,
+
+    #[arg(required = true)]
+    #[arg(
+        help = "Base path of the program and its hex*, lsq, and msq files, as given to noontide-emu"
+    )]
+    base_path: Option<String>,
+
+    #[arg(long)]
+    #[arg(help = "Hide msq implementation details at and below this depth")]
+    msq_depth: Option<usize>,
+
+    #[arg(long)]
+    #[arg(help = "Generate the report even if the .perf file was recorded from a different .bin")]
+    force: bool,
 
-    #[arg(help = "Base path to hex*, lsq, and msq files, without the file extension")]
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    #[arg(help = "Output format of the report")]
+    format: OutputFormat,
+
+    #[arg(long)]
+    #[arg(help = "Report hits per address instead of per line (json and csv only)")]
+    per_address: bool,
+
+    #[arg(long)]
+    #[arg(help = "Write an annotated source listing as a single HTML page to this path")]
+    html: Option<String>,
+}
+
+#[derive(Args)]
+struct DiffArgs {
+    #[arg(help = "Path to the .perf file recorded before the change")]
+    old_perf_path: String,
+
+    #[arg(help = "Path to the .perf file recorded after the change")]
+    new_perf_path: String,
+
+    #[arg(
+        help = "Base path of the program and its hex*, lsq, and msq files, as given to noontide-emu"
+    )]
     base_path: String,
 
+    #[arg(long)]
+    #[arg(help = "Base path of the program the old .perf file was recorded from, if it differs")]
+    old_base_path: Option<String>,
+
     #[arg(long)]
     #[arg(help = "Hide msq implementation details at and below this depth")]
     msq_depth: Option<usize>,
+
+    #[arg(long)]
+    #[arg(help = "Generate the report even if a .perf file was recorded from a different .bin")]
+    force: bool,
 }
 
-fn main() {
-    let cli = Cli::parse();
-    let perf_path = cli.perf_path;
-    let base_path = cli.base_path;
-
-    let recorded_eips_hashmap: HashMap<u64, u64> =
-        deserialize_from(File::open(perf_path).unwrap()).unwrap();
-    let mut recorded_eips: Vec<(u64, u64)> = recorded_eips_hashmap.into_iter().collect();
-    recorded_eips.sort();
-
-    let debug_data = pdb::find_debug_data(&base_path, cli.msq_depth.unwrap_or(100));
-    let lines = debug_data.unwrap().offsets;
-    let mut i = 0;
-    let mut cur_hits = 0;
-    let mut total_hits = 0;
-    let mut hits_per_line: Vec<(u64, String)> = Vec::new();
-    for record in recorded_eips {
-        total_hits += record.1;
-
-        if i == lines.len() {
-            continue;
+// The base path of the program at path, without the extension, and the hash of the program
+fn load_program(path: &str) -> (String, u64) {
+    match loader::load_program(path) {
+        Ok((base_path, program)) => (base_path, program.hash()),
+        Err(e) => {
+            eprintln!("Error: {e}");
+            std::process::exit(1);
         }
+    }
+}
 
-        while i + 1 != lines.len() && lines[i + 1].0 <= record.0 {
-            hits_per_line.push((cur_hits, lines[i].1.clone()));
-            cur_hits = 0;
-            i += 1;
+fn load_perf(perf_path: &str, bin_hash: u64, force: bool) -> perf::PerfData {
+    let perf_data = match perf::PerfData::read(perf_path) {
+        Ok(perf_data) => perf_data,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            std::process::exit(1);
         }
+    };
 
-        cur_hits += record.1;
+    match perf_data.bin_hash {
+        Some(recorded_hash) => {
+            if recorded_hash != bin_hash {
+                let mismatch = format!(
+                    "{perf_path} was recorded from a different binary ({recorded_hash:#018x}, expected {bin_hash:#018x})"
+                );
+                if !force {
+                    eprintln!("Error: {mismatch}");
+                    std::process::exit(1);
+                }
+                eprintln!("Warning: {mismatch}, continuing because of --force");
+            }
+        }
+        None => eprintln!("Warning: {perf_path} uses the legacy format, skipping binary check"),
+    }
+
+    eprintln!(
+        "{perf_path}: CPUs: {}, instructions: {}, sampling: {:?}",
+        perf_data.cpu_count, perf_data.total_instructions, perf_data.sampling_mode
+    );
+
+    perf_data
+}
+
+// With all MSQ details, see pdb::hide_msq_details() for hiding them
+fn load_debug_data(base_path: &str) -> pdb::DebugData {
+    match pdb::find_debug_data(base_path, 100) {
+        Some(debug_data) => debug_data,
+        None => {
+            eprintln!("Error: Missing hex0, hex1, hex2, or lsq file for {base_path}");
+            std::process::exit(1);
+        }
+    }
+}
+
+fn percentage(hits: u64, total_hits: u64) -> f64 {
+    if total_hits == 0 {
+        0.0
+    } else {
+        (hits as f64 * 100.0) / total_hits as f64
+    }
+}
+
+fn report(args: ReportArgs) {
+    let perf_path = args.perf_path.unwrap();
+    let (base_path, bin_hash) = load_program(&args.base_path.unwrap());
+    if args.per_address && (args.format == OutputFormat::Text || args.html.is_some()) {
+        eprintln!("Error: --per-address needs --format json or --format csv");
+        std::process::exit(1);
+    }
+
+    let perf_data = load_perf(&perf_path, bin_hash, args.force);
+    let recorded_eips = perf_data.merged_hits();
+    let total_hits: u64 = recorded_eips.iter().map(|record| record.1).sum();
+
+    let full_debug_data = load_debug_data(&base_path);
+    if let Some(html_path) = args.html {
+        // The listing folds MSQ regions instead of hiding them
+        let page = html::render_report(
+            &perf_path,
+            &perf_data,
+            &full_debug_data,
+            args.msq_depth.unwrap_or(100),
+        );
+        std::fs::write(html_path, page).unwrap();
+        return;
     }
 
-    for (hits, line) in hits_per_line {
-        let percentage = (hits as f64 * 100.0) / total_hits as f64;
+    let debug_data = pdb::hide_msq_details(&full_debug_data, args.msq_depth.unwrap_or(100));
+    if args.format != OutputFormat::Text {
+        let records = if args.per_address {
+            address_records(&debug_data, &recorded_eips, total_hits)
+        } else {
+            line_records(&debug_data, &recorded_eips, total_hits)
+        };
+
+        if args.format == OutputFormat::Json {
+            let report = JsonReport {
+                bin_hash: perf_data.bin_hash,
+                cpu_count: perf_data.cpu_count,
+                total_instructions: perf_data.total_instructions,
+                sampling_mode: perf_data.sampling_mode,
+                total_hits,
+                records,
+            };
+            println!("{}", serde_json::to_string_pretty(&report).unwrap());
+        } else {
+            print_csv(&records);
+        }
+        return;
+    }
+
+    let hits_per_line = perf::hits_per_line(&debug_data, &recorded_eips);
+    for (hits, (_offset, line)) in hits_per_line.into_iter().zip(debug_data.offsets) {
+        let percentage = percentage(hits, total_hits);
         let hits_str = if hits == 0 {
             "".to_owned()
         } else {
@@ -72,3 +224,372 @@ fn main() {
         }
     }
 }
+
+#[derive(Serialize)]
+struct HitRecord {
+    // 1-based index into the reported lines. This is the line number in the hex* or lsq file
+    // unless --msq-depth folded some MSQ regions into a single line.
+    line: usize,
+    offset: u64,
+    // Only set for per-address records
+    address: Option<u64>,
+    hits: u64,
+    percentage: f64,
+    text: String,
+}
+
+#[derive(Serialize)]
+struct JsonReport {
+    bin_hash: Option<u64>,
+    cpu_count: u64,
+    total_instructions: u64,
+    sampling_mode: perf::SamplingMode,
+    total_hits: u64,
+    records: Vec<HitRecord>,
+}
+
+fn line_records(
+    debug_data: &pdb::DebugData,
+    recorded_eips: &[(u64, u64)],
+    total_hits: u64,
+) -> Vec<HitRecord> {
+    perf::hits_per_line(debug_data, recorded_eips)
+        .into_iter()
+        .zip(&debug_data.offsets)
+        .enumerate()
+        .map(|(i, (hits, (offset, text)))| HitRecord {
+            line: i + 1,
+            offset: *offset,
+            address: None,
+            hits,
+            percentage: percentage(hits, total_hits),
+            text: text.clone(),
+        })
+        .collect()
+}
+
+fn address_records(
+    debug_data: &pdb::DebugData,
+    recorded_eips: &[(u64, u64)],
+    total_hits: u64,
+) -> Vec<HitRecord> {
+    recorded_eips
+        .iter()
+        .map(|(eip, hits)| {
+            let i = pdb::find_line(debug_data, *eip);
+            let (offset, text) = &debug_data.offsets[i];
+            HitRecord {
+                line: i + 1,
+                offset: *offset,
+                address: Some(*eip),
+                hits: *hits,
+                percentage: percentage(*hits, total_hits),
+                text: text.clone(),
+            }
+        })
+        .collect()
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+fn print_csv(records: &[HitRecord]) {
+    println!("line,offset,address,hits,percentage,text");
+    for record in records {
+        let address = match record.address {
+            Some(address) => address.to_string(),
+            None => "".to_owned(),
+        };
+        println!(
+            "{},{},{},{},{:.4},{}",
+            record.line,
+            record.offset,
+            address,
+            record.hits,
+            record.percentage,
+            csv_field(&record.text)
+        );
+    }
+}
+
+// Keys lines (or regions) by their text and how many times that text appeared before, so
+// that the two profiles can be lined up even if lines were added or removed in between
+fn occurrence_keys<'a>(texts: impl Iterator<Item = &'a str>) -> Vec<(String, usize)> {
+    let mut seen: HashMap<&str, usize> = HashMap::new();
+    texts
+        .map(|text| {
+            let count = seen.entry(text).or_insert(0);
+            *count += 1;
+            (text.to_owned(), *count)
+        })
+        .collect()
+}
+
+// Lines up the entries of both profiles, following the order of the new one and appending
+// the entries that only exist in the old one
+fn line_up(
+    old: Vec<((String, usize), u64)>,
+    new: Vec<((String, usize), u64)>,
+) -> Vec<(String, u64, u64)> {
+    let mut old_hits: HashMap<(String, usize), u64> = HashMap::new();
+    let mut old_only: Vec<(String, usize)> = Vec::new();
+    for (key, hits) in old {
+        old_only.push(key.clone());
+        old_hits.insert(key, hits);
+    }
+
+    let mut ret: Vec<(String, u64, u64)> = Vec::new();
+    for (key, hits) in new {
+        let old = old_hits.remove(&key).unwrap_or(0);
+        ret.push((key.0, old, hits));
+    }
+
+    for key in old_only {
+        if let Some(old) = old_hits.remove(&key) {
+            ret.push((key.0, old, 0));
+        }
+    }
+
+    ret
+}
+
+struct DiffTotals {
+    old_hits: u64,
+    new_hits: u64,
+    // Instructions per hit, so that the ratio stays meaningful across sampling modes
+    old_weight: u64,
+    new_weight: u64,
+}
+
+// How many times more instructions the new profile spent there, in floating point since hits
+// times the sample weight can overflow
+fn ratio(old: u64, new: u64, totals: &DiffTotals) -> String {
+    if old == 0 {
+        return "new".to_owned();
+    }
+
+    let old_instructions = old as f64 * totals.old_weight as f64;
+    let new_instructions = new as f64 * totals.new_weight as f64;
+    format!("{:.2}x", new_instructions / old_instructions)
+}
+
+fn print_diff_row(text: &str, old: u64, new: u64, totals: &DiffTotals) {
+    let old_percentage = percentage(old, totals.old_hits);
+    let new_percentage = percentage(new, totals.new_hits);
+    let delta = new_percentage - old_percentage;
+
+    if old == 0 && new == 0 {
+        println!("{: >8} {: >8} {: >8} {: >7} | {}", "", "", "", "", text);
+        return;
+    }
+
+    let ratio = ratio(old, new, totals);
+    let row = format!(
+        "{: >8.2} {: >8.2} {: >+8.2} {: >7} | {}",
+        old_percentage, new_percentage, delta, ratio, text
+    );
+    let colored_row: ColoredString = if delta >= 1.0 {
+        row.red()
+    } else if delta <= -1.0 {
+        row.green()
+    } else if delta.abs() >= 0.1 {
+        row.yellow()
+    } else {
+        row.normal()
+    };
+    println!("{}", colored_row);
+}
+
+fn diff(args: DiffArgs) {
+    let (base_path, bin_hash) = load_program(&args.base_path);
+    let (old_base_path, old_bin_hash) = match &args.old_base_path {
+        Some(old_base_path) => load_program(old_base_path),
+        None => (base_path.clone(), bin_hash),
+    };
+    let msq_depth = args.msq_depth.unwrap_or(100);
+
+    let old_perf = load_perf(&args.old_perf_path, old_bin_hash, args.force);
+    let new_perf = load_perf(&args.new_perf_path, bin_hash, args.force);
+    let old_hits = old_perf.merged_hits();
+    let new_hits = new_perf.merged_hits();
+    let totals = DiffTotals {
+        old_hits: old_hits.iter().map(|record| record.1).sum(),
+        new_hits: new_hits.iter().map(|record| record.1).sum(),
+        old_weight: old_perf.sample_weight(),
+        new_weight: new_perf.sample_weight(),
+    };
+
+    // Regions are always computed on the full debug data, so that hidden regions still count
+    let old_full_debug_data = load_debug_data(&old_base_path);
+    let new_full_debug_data = load_debug_data(&base_path);
+    let old_debug_data = pdb::hide_msq_details(&old_full_debug_data, msq_depth);
+    let new_debug_data = pdb::hide_msq_details(&new_full_debug_data, msq_depth);
+
+    let old_lines = occurrence_keys(old_debug_data.offsets.iter().map(|line| line.1.as_str()))
+        .into_iter()
+        .zip(perf::hits_per_line(&old_debug_data, &old_hits))
+        .collect();
+    let new_lines = occurrence_keys(new_debug_data.offsets.iter().map(|line| line.1.as_str()))
+        .into_iter()
+        .zip(perf::hits_per_line(&new_debug_data, &new_hits))
+        .collect();
+
+    println!(
+        "{: >8} {: >8} {: >8} {: >7} | Line",
+        "Old %", "New %", "Delta", "Ratio"
+    );
+    for (line, old, new) in line_up(old_lines, new_lines) {
+        print_diff_row(&line, old, new, &totals);
+    }
+
+    let old_regions = pdb::find_msq_regions(&old_full_debug_data);
+    let new_regions = pdb::find_msq_regions(&new_full_debug_data);
+    if old_regions.is_empty() && new_regions.is_empty() {
+        return;
+    }
+
+    let old_regions = occurrence_keys(old_regions.iter().map(|region| region.name.as_str()))
+        .into_iter()
+        .zip(perf::hits_per_region(
+            &old_full_debug_data,
+            &old_regions,
+            &old_hits,
+        ))
+        .collect();
+    let new_regions = occurrence_keys(new_regions.iter().map(|region| region.name.as_str()))
+        .into_iter()
+        .zip(perf::hits_per_region(
+            &new_full_debug_data,
+            &new_regions,
+            &new_hits,
+        ))
+        .collect();
+
+    println!();
+    println!(
+        "{: >8} {: >8} {: >8} {: >7} | MSQ region",
+        "Old %", "New %", "Delta", "Ratio"
+    );
+    for (region, old, new) in line_up(old_regions, new_regions) {
+        if old != 0 || new != 0 {
+            print_diff_row(&region, old, new, &totals);
+        }
+    }
+}
+
+fn main() {
+    let cli = Cli::parse();
+    match cli.command {
+        Some(Command::Diff(args)) => diff(args),
+        None => report(cli.report),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn debug_data(lines: &[(u64, &str)]) -> pdb::DebugData {
+        pdb::DebugData {
+            offsets: lines
+                .iter()
+                .map(|&(offset, text)| (offset, text.to_owned()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn csv_quoting() {
+        assert_eq!(csv_field("a b"), "a b");
+        assert_eq!(csv_field(""), "");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("a\nb"), "\"a\nb\"");
+        assert_eq!(csv_field("a\rb"), "\"a\rb\"");
+    }
+
+    #[test]
+    fn json_report() {
+        let debug_data = debug_data(&[(0, ":start \"quoted\" \\"), (24, "tab\there, ünïcode")]);
+        let hits = [(0, 3), (24, 1), (48, 4)];
+        let report = JsonReport {
+            bin_hash: Some(u64::MAX),
+            cpu_count: 1,
+            total_instructions: 800,
+            sampling_mode: perf::SamplingMode::Periodic(100),
+            total_hits: 8,
+            records: line_records(&debug_data, &hits, 8),
+        };
+        let json = serde_json::to_string_pretty(&report).unwrap();
+        let parsed: serde_json::Value = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(parsed["bin_hash"], u64::MAX);
+        assert_eq!(parsed["total_hits"], 8);
+        let records = parsed["records"].as_array().unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["line"], 1);
+        assert_eq!(records[0]["text"], ":start \"quoted\" \\");
+        assert_eq!(records[0]["address"], serde_json::Value::Null);
+        // The last line runs up to the end of memory
+        assert_eq!(records[1]["hits"], 5);
+        assert_eq!(records[1]["percentage"], 62.5);
+        assert_eq!(records[1]["text"], "tab\there, ünïcode");
+
+        let records = address_records(&debug_data, &hits, 8);
+        let addresses: Vec<_> = records.iter().map(|r| (r.address, r.line)).collect();
+        assert_eq!(addresses, [(Some(0), 1), (Some(24), 2), (Some(48), 2)]);
+    }
+
+    #[test]
+    fn lining_up_profiles() {
+        let keys = occurrence_keys(["a", "b", "a", "a"].into_iter());
+        assert_eq!(
+            keys,
+            [
+                ("a".to_owned(), 1),
+                ("b".to_owned(), 1),
+                ("a".to_owned(), 2),
+                ("a".to_owned(), 3)
+            ]
+        );
+
+        // A line added before the second "x", and "gone" removed
+        let old = occurrence_keys(["x", "gone", "x"].into_iter())
+            .into_iter()
+            .zip([1, 2, 3])
+            .collect();
+        let new = occurrence_keys(["x", "added", "x"].into_iter())
+            .into_iter()
+            .zip([4, 5, 6])
+            .collect();
+        assert_eq!(
+            line_up(old, new),
+            [
+                ("x".to_owned(), 1, 4),
+                ("added".to_owned(), 0, 5),
+                ("x".to_owned(), 3, 6),
+                ("gone".to_owned(), 2, 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn ratios() {
+        let totals = DiffTotals {
+            old_hits: 0,
+            new_hits: 0,
+            old_weight: 1000,
+            new_weight: 500,
+        };
+        assert_eq!(ratio(0, 5, &totals), "new");
+        assert_eq!(ratio(4, 4, &totals), "0.50x");
+        assert_eq!(ratio(1, 0, &totals), "0.00x");
+        // Would overflow in u64
+        assert_eq!(ratio(u64::MAX, u64::MAX, &totals), "0.50x");
+    }
+}
@@ -0,0 +1,175 @@
+use clap::Parser;
+
+use noontide_emu::{loader, pdb, trace};
+
+#[derive(Parser)]
+#[command(name = "noontide-trace")]
+#[command(author = "NyanCatTW1")]
+#[command(about = "Filter, search and display a .trace file", long_about = None)]
+struct Cli {
+    #[arg(help = "Path to the .trace file")]
+    trace_path: String,
+
+    #[arg(
+        help = "Base path of the program and its hex*, lsq, and msq files, as given to noontide-emu"
+    )]
+    base_path: Option<String>,
+
+    #[arg(long)]
+    #[arg(help = "Hide msq implementation details at and below this depth")]
+    msq_depth: Option<usize>,
+
+    #[arg(long)]
+    #[arg(help = "Display the trace even if it was recorded from a different .bin")]
+    force: bool,
+
+    #[arg(long)]
+    #[arg(help = "Only show instructions executed by this CPU")]
+    cpu: Option<u16>,
+
+    #[arg(long, value_parser = parse_u64)]
+    #[arg(help = "Only show instructions at this EIP")]
+    eip: Option<u64>,
+
+    #[arg(long, value_parser = parse_u64)]
+    #[arg(help = "Only show instructions writing to this address")]
+    write_to: Option<u64>,
+
+    #[arg(long, conflicts_with = "not_taken")]
+    #[arg(help = "Only show instructions whose branch was taken")]
+    taken: bool,
+
+    #[arg(long)]
+    #[arg(help = "Only show instructions whose branch was not taken")]
+    not_taken: bool,
+
+    #[arg(long)]
+    #[arg(help = "Only show instructions whose source line contains this text")]
+    search: Option<String>,
+
+    #[arg(long, default_value_t = 0)]
+    #[arg(help = "Skip this many matching instructions")]
+    skip: usize,
+
+    #[arg(long)]
+    #[arg(help = "Show at most this many matching instructions")]
+    limit: Option<usize>,
+
+    #[arg(long)]
+    #[arg(help = "Only print the number of matching instructions")]
+    count: bool,
+}
+
+fn parse_u64(inp: &str) -> Result<u64, String> {
+    let parsed = match inp.strip_prefix("0x").or(inp.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => inp.parse::<u64>(),
+    };
+
+    parsed.map_err(|e| format!("Invalid number {inp}: {e}"))
+}
+
+fn source_line(debug_data: &Option<pdb::DebugData>, eip: u64) -> Option<&str> {
+    let debug_data = debug_data.as_ref()?;
+    if debug_data.offsets.last().unwrap().0 <= eip {
+        return Some("(Beyond end of debug file)");
+    }
+
+    Some(&debug_data.offsets[pdb::find_line(debug_data, eip)].1)
+}
+
+fn main() {
+    let cli = Cli::parse();
+
+    let reader = match trace::TraceReader::open(&cli.trace_path) {
+        Ok(reader) => reader,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            std::process::exit(1);
+        }
+    };
+
+    let debug_data = match &cli.base_path {
+        Some(base_path) => {
+            let (base_path, program) = match loader::load_program(base_path) {
+                Ok(program) => program,
+                Err(e) => {
+                    eprintln!("Error: {e}");
+                    std::process::exit(1);
+                }
+            };
+
+            let bin_hash = program.hash();
+            if reader.bin_hash != bin_hash {
+                let mismatch = format!(
+                    "{} was recorded from a different binary ({:#018x}, expected {bin_hash:#018x})",
+                    cli.trace_path, reader.bin_hash
+                );
+                if !cli.force {
+                    eprintln!("Error: {mismatch}");
+                    std::process::exit(1);
+                }
+                eprintln!("Warning: {mismatch}, continuing because of --force");
+            }
+
+            pdb::find_debug_data(&base_path, cli.msq_depth.unwrap_or(100))
+                .filter(|debug_data| !debug_data.offsets.is_empty())
+        }
+        None => None,
+    };
+
+    if cli.search.is_some() && debug_data.is_none() {
+        eprintln!("Error: --search requires the hex*/lsq files of the program");
+        std::process::exit(1);
+    }
+
+    let mut matches = 0;
+    let mut shown = 0;
+    for (i, record) in reader.enumerate() {
+        if cli.cpu.is_some_and(|cpu| record.cpu != cpu)
+            || cli.eip.is_some_and(|eip| record.eip != eip)
+            || cli.write_to.is_some_and(|addr| record.a_addr != addr)
+            || (cli.taken && !record.branch_taken)
+            || (cli.not_taken && record.branch_taken)
+        {
+            continue;
+        }
+
+        let line = source_line(&debug_data, record.eip);
+        if let Some(search) = &cli.search {
+            if !line.unwrap().contains(search.as_str()) {
+                continue;
+            }
+        }
+
+        matches += 1;
+        if cli.count || matches <= cli.skip {
+            continue;
+        }
+
+        if cli.limit.is_some_and(|limit| shown >= limit) {
+            break;
+        }
+        shown += 1;
+
+        let branch = if record.branch_taken { "taken" } else { "next" };
+        let out = format!(
+            "{i: >10} CPU {} {:#010x}: [{:#x}] {:#x} - {:#x} = {:#x}, {branch}",
+            record.cpu,
+            record.eip,
+            record.a_addr,
+            record.a_before,
+            record.b_val,
+            record.a_after()
+        );
+
+        match line {
+            Some(line) => println!("{out: <80} | {line}"),
+            None => println!("{out}"),
+        }
+    }
+
+    if cli.count {
+        println!("{matches}");
+    }
+}
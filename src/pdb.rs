@@ -22,7 +22,8 @@ pub fn parse_hex_file(inp: &str) -> DebugData {
                 break;
             }
 
-            if c == ' ' {
+            // Label names end at any whitespace, as in assembler::assemble()
+            if c.is_whitespace() {
                 wait_for_space = false;
             }
 
@@ -112,7 +113,7 @@ pub fn parse_lsq_file(inp: &str) -> DebugData {
     ret
 }
 
-pub fn hide_msq_details(debug_data: DebugData, msq_depth: usize) -> DebugData {
+pub fn hide_msq_details(debug_data: &DebugData, msq_depth: usize) -> DebugData {
     let mut ret: DebugData = DebugData {
         offsets: Vec::new(),
     };
@@ -141,31 +142,71 @@ pub fn hide_msq_details(debug_data: DebugData, msq_depth: usize) -> DebugData {
     ret
 }
 
-pub fn find_debug_data(base_path: &str, msq_depth: usize) -> Option<DebugData> {
-    let mut debug_data: Option<DebugData> = None;
-    for ext in ["hex0", "hex1", "hex2"] {
-        let mut hex_path_str = base_path.to_owned();
-        hex_path_str.push('.');
-        hex_path_str.push_str(ext);
-        let hex_path = std::path::Path::new(&hex_path_str);
-        if !hex_path.exists() {
-            continue;
-        }
+// Path of the file find_debug_data() reads, lsq taking priority over hex2, hex1, and hex0
+pub fn find_debug_source(base_path: &str) -> Option<String> {
+    ["lsq", "hex2", "hex1", "hex0"]
+        .iter()
+        .map(|ext| format!("{base_path}.{ext}"))
+        .find(|path| std::path::Path::new(path).exists())
+}
 
-        debug_data = Some(parse_hex_file(&std::fs::read_to_string(hex_path).unwrap()));
+pub fn find_debug_data(base_path: &str, msq_depth: usize) -> Option<DebugData> {
+    let path = find_debug_source(base_path)?;
+    let inp = std::fs::read_to_string(&path).unwrap();
+    if path.ends_with(".lsq") {
+        Some(hide_msq_details(&parse_lsq_file(&inp), msq_depth))
+    } else {
+        Some(parse_hex_file(&inp))
     }
+}
 
-    let mut lsq_path_str = base_path.to_owned();
-    lsq_path_str.push_str(".lsq");
-    let lsq_path = std::path::Path::new(&lsq_path_str);
-    if lsq_path.exists() {
-        debug_data = Some(hide_msq_details(
-            parse_lsq_file(&std::fs::read_to_string(lsq_path).unwrap()),
-            msq_depth,
-        ));
+// Labels defined with ":name" in hex2 files, along with their addresses
+pub fn find_labels(debug_data: &DebugData) -> HashMap<String, u64> {
+    let mut ret = HashMap::new();
+    for (offset, line) in &debug_data.offsets {
+        // Read the same way as assembler::assemble() does
+        let mut hex_chars = 0;
+        let mut rest = line.split(['#', ';']).next().unwrap();
+        while let Some(c) = rest.chars().next() {
+            if c == ':' || c == '&' || c == '?' {
+                let len = rest.find(char::is_whitespace).unwrap_or(rest.len());
+                if c == ':' {
+                    ret.insert(rest[1..len].to_owned(), offset + hex_chars / 2);
+                } else {
+                    hex_chars += 16;
+                }
+                rest = &rest[len..];
+                continue;
+            }
+
+            if c.is_ascii_hexdigit() {
+                hex_chars += 1;
+            }
+            rest = &rest[c.len_utf8()..];
+        }
     }
 
+    ret
+}
+
+// Index of the line that EIP belongs to, i.e. the last line starting at or before it
+pub fn find_line(debug_data: &DebugData, eip: u64) -> usize {
     debug_data
+        .offsets
+        .partition_point(|line| line.0 <= eip)
+        .saturating_sub(1)
+}
+
+// count lines of the source starting at start, with line numbers and an arrow at EIP's line
+pub fn render_source(debug_data: &DebugData, eip: u64, start: usize, count: usize) -> Vec<String> {
+    let eip_line = (eip < debug_data.offsets.last().unwrap().0).then(|| find_line(debug_data, eip));
+    let end = std::cmp::min(debug_data.offsets.len(), start.saturating_add(count));
+    (start..end)
+        .map(|i| {
+            let marker = if Some(i) == eip_line { "->" } else { "  " };
+            format!("{marker}{:>6}  {}", i + 1, debug_data.offsets[i].1)
+        })
+        .collect()
 }
 
 pub fn memory_dump(mem: &[u8]) -> String {
@@ -195,7 +236,10 @@ pub fn render_debug(
     batch: bool,
 ) -> (isize, String) {
     let Some(debug_data) = debug_data else {
-        return (-1, "Error: Missing hex0, hex1, hex2, or lsq file for debugging".to_owned());
+        return (
+            -1,
+            "Error: Missing hex0, hex1, hex2, or lsq file for debugging".to_owned(),
+        );
     };
 
     let mut ret_lines: Vec<String> = Vec::new();
@@ -213,11 +257,7 @@ pub fn render_debug(
     }
     cur_line -= 1;
 
-    let start = if lines > cur_line {
-        0
-    } else {
-        cur_line - lines
-    };
+    let start = cur_line.saturating_sub(lines);
 
     let end = std::cmp::min(debug_data.offsets.len(), cur_line + lines + 1);
     for i in start..end {
@@ -235,3 +275,50 @@ pub fn render_debug(
         (cur_line.try_into().unwrap(), ret_lines.join("\r\n"))
     }
 }
+
+pub struct MsqRegion {
+    pub name: String,
+    pub depth: usize,
+    // Indices into DebugData::offsets of the MSQ_START and MSQ_END lines
+    pub start: usize,
+    pub end: usize,
+}
+
+pub fn find_msq_regions(debug_data: &DebugData) -> Vec<MsqRegion> {
+    let mut ret: Vec<MsqRegion> = Vec::new();
+    let mut open_regions: Vec<(usize, String, usize)> = Vec::new();
+
+    for (i, (_offset, line)) in debug_data.offsets.iter().enumerate() {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        if tokens.len() < 3 || tokens[0] != "rem" {
+            continue;
+        }
+
+        let Ok(depth) = tokens[2].parse::<usize>() else {
+            continue;
+        };
+
+        match tokens[1] {
+            "MSQ_START" => {
+                open_regions.push((depth, tokens[3..].join(" "), i));
+            }
+            "MSQ_END" => {
+                while let Some((start_depth, name, start)) = open_regions.pop() {
+                    if start_depth == depth {
+                        ret.push(MsqRegion {
+                            name,
+                            depth,
+                            start,
+                            end: i,
+                        });
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    ret.sort_by_key(|region| region.start);
+    ret
+}
@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::pdb::{DebugData, MsqRegion};
+
+// A .perf file is laid out as:
+//   magic (8 bytes) | version (u32 LE) | body (bincode PerfData) | checksum of body (u64 LE)
+// Files without the magic are assumed to be the legacy format, which is a bare bincode
+// HashMap<u64, u64> of EIP -> hits.
+pub const PERF_MAGIC: [u8; 8] = *b"NTPERF\0\0";
+pub const PERF_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SamplingMode {
+    // Unknown sampling, used for legacy files
+    Unknown,
+    // The EIP of a CPU is recorded once every N instructions
+    Periodic(u32),
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PerfData {
+    // Hash of the .bin the profile was recorded from, None for legacy files
+    pub bin_hash: Option<u64>,
+    pub cpu_count: u64,
+    pub total_instructions: u64,
+    pub sampling_mode: SamplingMode,
+    // EIP -> hits, one table per CPU
+    pub cpus: Vec<HashMap<u64, u64>>,
+}
+
+// 64-bit FNV-1a, chosen over std's DefaultHasher because its output must stay stable
+// between builds
+pub fn hash_bytes(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in data {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+
+    hash
+}
+
+impl PerfData {
+    pub fn new(
+        bin_hash: u64,
+        total_instructions: u64,
+        sampling_mode: SamplingMode,
+        cpus: Vec<HashMap<u64, u64>>,
+    ) -> Self {
+        PerfData {
+            bin_hash: Some(bin_hash),
+            cpu_count: cpus.len() as u64,
+            total_instructions,
+            sampling_mode,
+            cpus,
+        }
+    }
+
+    // Number of instructions each hit stands for
+    pub fn sample_weight(&self) -> u64 {
+        match self.sampling_mode {
+            SamplingMode::Unknown => 1,
+            SamplingMode::Periodic(interval) => interval as u64,
+        }
+    }
+
+    // Hits of all CPUs combined, sorted by EIP
+    pub fn merged_hits(&self) -> Vec<(u64, u64)> {
+        let mut merged: HashMap<u64, u64> = HashMap::new();
+        for cpu in &self.cpus {
+            for (eip, hits) in cpu {
+                *merged.entry(*eip).or_insert(0) += hits;
+            }
+        }
+
+        let mut ret: Vec<(u64, u64)> = merged.into_iter().collect();
+        ret.sort();
+        ret
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let body = bincode::serialize(self).unwrap();
+
+        let mut ret = Vec::with_capacity(body.len() + 20);
+        ret.extend_from_slice(&PERF_MAGIC);
+        ret.extend_from_slice(&PERF_VERSION.to_le_bytes());
+        ret.extend_from_slice(&body);
+        ret.extend_from_slice(&hash_bytes(&body).to_le_bytes());
+        ret
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, String> {
+        if !data.starts_with(&PERF_MAGIC) {
+            let legacy: HashMap<u64, u64> = bincode::deserialize(data)
+                .map_err(|e| format!("Not a valid .perf file (legacy format): {e}"))?;
+            return Ok(PerfData {
+                bin_hash: None,
+                cpu_count: 1,
+                total_instructions: 0,
+                sampling_mode: SamplingMode::Unknown,
+                cpus: vec![legacy],
+            });
+        }
+
+        if data.len() < PERF_MAGIC.len() + 4 + 8 {
+            return Err("Truncated .perf file".to_owned());
+        }
+
+        let version = u32::from_le_bytes(data[8..12].try_into().unwrap());
+        if version != PERF_VERSION {
+            return Err(format!(
+                "Unsupported .perf version {version} (expected {PERF_VERSION})"
+            ));
+        }
+
+        let (body, checksum) = data[12..].split_at(data.len() - 12 - 8);
+        if hash_bytes(body) != u64::from_le_bytes(checksum.try_into().unwrap()) {
+            return Err("Checksum mismatch, the .perf file is corrupt".to_owned());
+        }
+
+        let ret: PerfData =
+            bincode::deserialize(body).map_err(|e| format!("Corrupt .perf file: {e}"))?;
+        if ret.cpus.len() as u64 != ret.cpu_count {
+            return Err(format!(
+                "Corrupt .perf file: header claims {} CPUs, found {}",
+                ret.cpu_count,
+                ret.cpus.len()
+            ));
+        }
+
+        Ok(ret)
+    }
+
+    pub fn read(path: &str) -> Result<Self, String> {
+        let data = std::fs::read(path).map_err(|e| format!("Failed to read {path}: {e}"))?;
+        PerfData::from_bytes(&data)
+    }
+
+    pub fn write(&self, path: &str) {
+        std::fs::write(path, self.to_bytes()).unwrap();
+    }
+}
+
+// Sum of the hits with start <= EIP < end, hits must be sorted by EIP
+pub fn hits_in_range(hits: &[(u64, u64)], start: u64, end: u64) -> u64 {
+    let first = hits.partition_point(|record| record.0 < start);
+    let last = hits.partition_point(|record| record.0 < end);
+    hits[first..last].iter().map(|record| record.1).sum()
+}
+
+// Hits attributed to each line of the debug data, hits must be sorted by EIP
+pub fn hits_per_line(debug_data: &DebugData, hits: &[(u64, u64)]) -> Vec<u64> {
+    let lines = &debug_data.offsets;
+    (0..lines.len())
+        .map(|i| {
+            let end = if i + 1 == lines.len() {
+                u64::MAX
+            } else {
+                lines[i + 1].0
+            };
+            hits_in_range(hits, lines[i].0, end)
+        })
+        .collect()
+}
+
+// Hits attributed to each MSQ region, including the regions nested inside of it
+pub fn hits_per_region(
+    debug_data: &DebugData,
+    regions: &[MsqRegion],
+    hits: &[(u64, u64)],
+) -> Vec<u64> {
+    regions
+        .iter()
+        .map(|region| {
+            hits_in_range(
+                hits,
+                debug_data.offsets[region.start].0,
+                debug_data.offsets[region.end].0,
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> PerfData {
+        PerfData::new(
+            0x1234,
+            1000,
+            SamplingMode::Periodic(100),
+            vec![HashMap::from([(0, 3), (24, 5)]), HashMap::from([(24, 2)])],
+        )
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = PerfData::from_bytes(&sample().to_bytes()).unwrap();
+        assert_eq!(data.bin_hash, Some(0x1234));
+        assert_eq!(data.cpu_count, 2);
+        assert_eq!(data.total_instructions, 1000);
+        assert_eq!(data.sampling_mode, SamplingMode::Periodic(100));
+        assert_eq!(data.merged_hits(), vec![(0, 3), (24, 7)]);
+    }
+
+    #[test]
+    fn legacy_format() {
+        let legacy = bincode::serialize(&HashMap::from([(48u64, 9u64)])).unwrap();
+        let data = PerfData::from_bytes(&legacy).unwrap();
+        assert_eq!(data.bin_hash, None);
+        assert_eq!(data.sampling_mode, SamplingMode::Unknown);
+        assert_eq!(data.merged_hits(), vec![(48, 9)]);
+    }
+
+    #[test]
+    fn corrupt_files() {
+        let bytes = sample().to_bytes();
+
+        let mut flipped = bytes.clone();
+        flipped[20] ^= 1;
+        assert!(PerfData::from_bytes(&flipped)
+            .err()
+            .unwrap()
+            .contains("Checksum mismatch"));
+
+        assert!(PerfData::from_bytes(&bytes[..15])
+            .err()
+            .unwrap()
+            .contains("Truncated"));
+
+        let mut version = bytes.clone();
+        version[8] = 2;
+        assert!(PerfData::from_bytes(&version)
+            .err()
+            .unwrap()
+            .contains("Unsupported .perf version"));
+
+        assert!(PerfData::from_bytes(b"garbage").is_err());
+    }
+}
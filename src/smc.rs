@@ -0,0 +1,253 @@
+use crate::{exec::Executed, pdb::DebugData};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SmcEvent {
+    // Code that has already been executed was overwritten
+    CodeModified {
+        addr: u64,
+        writer_eip: u64,
+    },
+    // An instruction was executed after one of its words was overwritten
+    ModifiedCodeExecuted {
+        eip: u64,
+        addr: u64,
+        writer_eip: u64,
+    },
+}
+
+fn source_line(debug_data: &Option<DebugData>, addr: u64) -> &str {
+    match debug_data {
+        Some(debug_data) if addr < debug_data.offsets.last().unwrap().0 => {
+            &debug_data.offsets[crate::pdb::find_line(debug_data, addr)].1
+        }
+        _ => "(No debug info)",
+    }
+}
+
+impl SmcEvent {
+    pub fn describe(&self, debug_data: &Option<DebugData>) -> String {
+        match *self {
+            SmcEvent::CodeModified { addr, writer_eip } => format!(
+                "Code at {addr:#x} modified by {writer_eip:#x}\n  code:   {}\n  writer: {}",
+                source_line(debug_data, addr),
+                source_line(debug_data, writer_eip)
+            ),
+            SmcEvent::ModifiedCodeExecuted {
+                eip,
+                addr,
+                writer_eip,
+            } => format!(
+                "Executed {eip:#x} after {addr:#x} was modified by {writer_eip:#x}\n  code:   {}\n  writer: {}",
+                source_line(debug_data, eip),
+                source_line(debug_data, writer_eip)
+            ),
+        }
+    }
+}
+
+// Watches the code loaded from the .bin for self-modification. Every 8-byte word in the
+// watched range remembers whether it has been executed as part of an instruction and which
+// EIP last changed it. Each word is only reported once per kind of event, since SUBLEQ code
+// patching the same operand in a loop would otherwise flood the output.
+pub struct SmcTracker {
+    start: u64,
+    end: u64,
+    // One bit per word
+    executed: Vec<u64>,
+    reported_write: Vec<u64>,
+    reported_exec: Vec<u64>,
+    // Writer EIP + 1 for every word, 0 if it still holds what was loaded
+    writers: Vec<u64>,
+}
+
+fn test_bit(bits: &[u64], i: usize) -> bool {
+    bits[i >> 6] & (1 << (i & 63)) != 0
+}
+
+fn set_bit(bits: &mut [u64], i: usize) {
+    bits[i >> 6] |= 1 << (i & 63);
+}
+
+impl SmcTracker {
+    // Watches [start, end), which is rounded out to whole words
+    pub fn new(start: u64, end: u64) -> Self {
+        let start = start & !7;
+        let end = std::cmp::max(start, (end + 7) & !7);
+        let words = ((end - start) / 8) as usize;
+        let bit_words = words / 64 + 1;
+        SmcTracker {
+            start,
+            end,
+            executed: vec![0; bit_words],
+            reported_write: vec![0; bit_words],
+            reported_exec: vec![0; bit_words],
+            writers: vec![0; words],
+        }
+    }
+
+    // Watches the part of the .bin that the debug data describes, or all of it without any
+    pub fn for_program(bin_len: u64, debug_data: &Option<DebugData>) -> Self {
+        let end = match debug_data {
+            Some(debug_data) => std::cmp::min(bin_len, debug_data.offsets.last().unwrap().0),
+            None => bin_len,
+        };
+        SmcTracker::new(0, end)
+    }
+
+    // Indices of the watched words overlapping the len bytes at addr
+    fn words(&self, addr: u64, len: u64) -> std::ops::Range<usize> {
+        let first = std::cmp::max(addr, self.start);
+        let last = std::cmp::min(addr.saturating_add(len), self.end);
+        if first >= last {
+            return 0..0;
+        }
+
+        ((first - self.start) / 8) as usize..((last - 1 - self.start) / 8 + 1) as usize
+    }
+
+    // Must be called for every executed instruction, in order
+    pub fn check(&mut self, ins: &Executed, mut report: impl FnMut(SmcEvent)) {
+        // The instruction was read before its own write happened
+        for word in self.words(ins.eip, 24) {
+            set_bit(&mut self.executed, word);
+
+            let writer = self.writers[word];
+            if writer != 0 && !test_bit(&self.reported_exec, word) {
+                set_bit(&mut self.reported_exec, word);
+                report(SmcEvent::ModifiedCodeExecuted {
+                    eip: ins.eip,
+                    addr: self.start + 8 * word as u64,
+                    writer_eip: writer - 1,
+                });
+            }
+        }
+
+        // Subtracting 0 leaves the code as it was
+        if ins.b_val == 0 {
+            return;
+        }
+
+        for word in self.words(ins.a_addr, 8) {
+            self.writers[word] = ins.eip + 1;
+
+            if test_bit(&self.executed, word) && !test_bit(&self.reported_write, word) {
+                set_bit(&mut self.reported_write, word);
+                report(SmcEvent::CodeModified {
+                    addr: self.start + 8 * word as u64,
+                    writer_eip: ins.eip,
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ins(eip: u64, a_addr: u64, b_val: i64) -> Executed {
+        Executed {
+            eip,
+            a_addr,
+            b_addr: 0x1000,
+            c_addr: 0,
+            a_before: 0,
+            b_val,
+        }
+    }
+
+    fn check(tracker: &mut SmcTracker, ins: Executed) -> Vec<SmcEvent> {
+        let mut events = Vec::new();
+        tracker.check(&ins, |event| events.push(event));
+        events
+    }
+
+    #[test]
+    fn only_executed_code_is_reported() {
+        let mut tracker = SmcTracker::new(0, 96);
+        assert_eq!(check(&mut tracker, ins(0, 0x1000, 1)), []);
+
+        // Not executed yet, then executed after the write
+        assert_eq!(check(&mut tracker, ins(24, 48, 1)), []);
+        assert_eq!(
+            check(&mut tracker, ins(48, 0x1000, 1)),
+            [SmcEvent::ModifiedCodeExecuted {
+                eip: 48,
+                addr: 48,
+                writer_eip: 24
+            }]
+        );
+
+        // Already executed, which includes the writing instruction itself
+        assert_eq!(
+            check(&mut tracker, ins(24, 8, 1)),
+            [SmcEvent::CodeModified {
+                addr: 8,
+                writer_eip: 24
+            }]
+        );
+        assert_eq!(
+            check(&mut tracker, ins(24, 32, 1)),
+            [SmcEvent::CodeModified {
+                addr: 32,
+                writer_eip: 24
+            }]
+        );
+
+        // Reported once per word, and subtracting 0 or writing outside isn't a write
+        assert_eq!(check(&mut tracker, ins(0x2000, 8, 1)), []);
+        assert_eq!(check(&mut tracker, ins(0x2000, 16, 0)), []);
+        assert_eq!(check(&mut tracker, ins(0x2000, 96, 1)), []);
+        assert_eq!(check(&mut tracker, ins(72, 0x1000, 1)), []);
+    }
+
+    #[test]
+    fn partial_and_overlapping_writes() {
+        let mut tracker = SmcTracker::new(0, 48);
+        check(&mut tracker, ins(0, 0x1000, 1));
+
+        // Straddling the last executed word and the next one
+        assert_eq!(
+            check(&mut tracker, ins(0, 20, 1)),
+            [SmcEvent::CodeModified {
+                addr: 16,
+                writer_eip: 0
+            }]
+        );
+        // Only partly inside the watched range, and not executed yet
+        assert_eq!(check(&mut tracker, ins(0x2000, 44, 1)), []);
+        assert_eq!(
+            check(&mut tracker, ins(24, 0x1000, 1)),
+            [
+                SmcEvent::ModifiedCodeExecuted {
+                    eip: 24,
+                    addr: 24,
+                    writer_eip: 0
+                },
+                SmcEvent::ModifiedCodeExecuted {
+                    eip: 24,
+                    addr: 40,
+                    writer_eip: 0x2000
+                }
+            ]
+        );
+
+        // Unaligned ranges are rounded out to whole words
+        let mut tracker = SmcTracker::new(4, 13);
+        check(&mut tracker, ins(0, 0x1000, 1));
+        let events = check(&mut tracker, ins(0x2000, 2, 1));
+        assert_eq!(
+            events,
+            [
+                SmcEvent::CodeModified {
+                    addr: 0,
+                    writer_eip: 0x2000
+                },
+                SmcEvent::CodeModified {
+                    addr: 8,
+                    writer_eip: 0x2000
+                }
+            ]
+        );
+    }
+}
@@ -0,0 +1,177 @@
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+};
+
+// A .snap file is laid out as:
+//   magic (8 bytes) | version (u32 LE) | memory size (u64 LE) | CPU count (u32 LE) | EIPs
+//   | page count (u64 LE) | pages
+// with every EIP being a u64 LE, and every page being its index (u64 LE) followed by its
+// PAGE_SIZE bytes. Pages that are all zeroes are left out.
+pub const SNAPSHOT_MAGIC: [u8; 8] = *b"NTSNAP\0\0";
+pub const SNAPSHOT_VERSION: u32 = 1;
+pub const PAGE_SIZE: usize = 4096;
+
+pub fn write(path: &str, mem: &[u8], eips: &[u64]) -> Result<(), String> {
+    let failed = |e: std::io::Error| format!("Failed to write {path}: {e}");
+    let mut out = BufWriter::new(File::create(path).map_err(failed)?);
+    out.write_all(&SNAPSHOT_MAGIC).map_err(failed)?;
+    out.write_all(&SNAPSHOT_VERSION.to_le_bytes())
+        .map_err(failed)?;
+    out.write_all(&(mem.len() as u64).to_le_bytes())
+        .map_err(failed)?;
+    out.write_all(&(eips.len() as u32).to_le_bytes())
+        .map_err(failed)?;
+    for eip in eips {
+        out.write_all(&eip.to_le_bytes()).map_err(failed)?;
+    }
+
+    let pages: Vec<(usize, &[u8])> = mem
+        .chunks(PAGE_SIZE)
+        .enumerate()
+        .filter(|(_i, page)| page.iter().any(|&b| b != 0))
+        .collect();
+    out.write_all(&(pages.len() as u64).to_le_bytes())
+        .map_err(failed)?;
+    for (i, page) in pages {
+        out.write_all(&(i as u64).to_le_bytes()).map_err(failed)?;
+        out.write_all(page).map_err(failed)?;
+    }
+    out.flush().map_err(failed)
+}
+
+// Loads a snapshot into mem, which must be zeroed and as large as the snapshotted memory.
+// Returns the EIP of every CPU.
+pub fn read(path: &str, mem: &mut [u8]) -> Result<Vec<u64>, String> {
+    let file = File::open(path).map_err(|e| format!("Failed to open {path}: {e}"))?;
+    let mut inp = BufReader::new(file);
+    let truncated = |_| format!("{path} is truncated");
+
+    let mut header = [0u8; 8 + 4 + 8 + 4];
+    inp.read_exact(&mut header)
+        .map_err(|_| format!("{path} is too short to be a .snap file"))?;
+
+    if header[0..8] != SNAPSHOT_MAGIC {
+        return Err(format!("{path} is not a .snap file"));
+    }
+
+    let version = u32::from_le_bytes(header[8..12].try_into().unwrap());
+    if version != SNAPSHOT_VERSION {
+        return Err(format!(
+            "Unsupported .snap version {version} (expected {SNAPSHOT_VERSION})"
+        ));
+    }
+
+    let mem_len = u64::from_le_bytes(header[12..20].try_into().unwrap());
+    if mem_len != mem.len() as u64 {
+        return Err(format!(
+            "{path} has {mem_len:#x} bytes of memory, expected {:#x}",
+            mem.len()
+        ));
+    }
+
+    let cpu_count = u32::from_le_bytes(header[20..24].try_into().unwrap());
+    let mut eips = Vec::new();
+    let mut data = [0u8; 8];
+    for _ in 0..cpu_count {
+        inp.read_exact(&mut data).map_err(truncated)?;
+        eips.push(u64::from_le_bytes(data));
+    }
+
+    inp.read_exact(&mut data).map_err(truncated)?;
+    let page_count = u64::from_le_bytes(data);
+    for _ in 0..page_count {
+        inp.read_exact(&mut data).map_err(truncated)?;
+        // The last page is cut short if memory doesn't end on a page boundary
+        let page = usize::try_from(u64::from_le_bytes(data))
+            .ok()
+            .and_then(|i| i.checked_mul(PAGE_SIZE))
+            .filter(|&start| start < mem.len())
+            .map(|start| start..std::cmp::min(start.saturating_add(PAGE_SIZE), mem.len()));
+        let Some(page) = page.map(|range| &mut mem[range]) else {
+            return Err(format!("{path} contains a page outside of memory"));
+        };
+        inp.read_exact(page).map_err(truncated)?;
+    }
+
+    Ok(eips)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("{name}-{}.snap", std::process::id()));
+        path.to_str().unwrap().to_owned()
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = temp_path("round-trip");
+        // Not a whole number of pages, with the last one partly used
+        let mut mem = vec![0u8; 3 * PAGE_SIZE + 100];
+        mem[5] = 1;
+        mem[2 * PAGE_SIZE + 7] = 2;
+        mem[3 * PAGE_SIZE + 99] = 3;
+        write(&path, &mem, &[0x18, 0x30]).unwrap();
+
+        let mut loaded = vec![0u8; mem.len()];
+        assert_eq!(read(&path, &mut loaded), Ok(vec![0x18, 0x30]));
+        assert!(loaded == mem);
+
+        // Only the pages with something in them are written
+        let header = 8 + 4 + 8 + 4 + 2 * 8 + 8;
+        let len = std::fs::metadata(&path).unwrap().len() as usize;
+        assert_eq!(len, header + 2 * (8 + PAGE_SIZE) + 8 + 100);
+
+        let mut smaller = vec![0u8; PAGE_SIZE];
+        assert!(read(&path, &mut smaller).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn corrupt_files() {
+        let path = temp_path("corrupt");
+        let mut mem = vec![0u8; 2 * PAGE_SIZE];
+        mem[PAGE_SIZE] = 1;
+        write(&path, &mem, &[0]).unwrap();
+        let good = std::fs::read(&path).unwrap();
+        let page_index = 8 + 4 + 8 + 4 + 8 + 8;
+
+        let read_with = |edit: &dyn Fn(&mut Vec<u8>)| {
+            let mut data = good.clone();
+            edit(&mut data);
+            std::fs::write(&path, data).unwrap();
+            read(&path, &mut vec![0u8; mem.len()]).map_err(|e| e.replace(&path, "file"))
+        };
+
+        assert_eq!(read_with(&|_| {}), Ok(vec![0]));
+        assert_eq!(
+            read_with(&|data| data[0] = b'X'),
+            Err("file is not a .snap file".to_owned())
+        );
+        assert_eq!(
+            read_with(&|data| data[8] = 2),
+            Err("Unsupported .snap version 2 (expected 1)".to_owned())
+        );
+        assert_eq!(
+            read_with(&|data| data.truncate(10)),
+            Err("file is too short to be a .snap file".to_owned())
+        );
+        assert_eq!(
+            read_with(&|data| data.truncate(data.len() - 1)),
+            Err("file is truncated".to_owned())
+        );
+        // Page indices past the end of memory, including ones that overflow the address
+        for index in [2, u64::MAX / PAGE_SIZE as u64 + 1, u64::MAX] {
+            assert_eq!(
+                read_with(&|data| {
+                    data[page_index..page_index + 8].copy_from_slice(&index.to_le_bytes())
+                }),
+                Err("file contains a page outside of memory".to_owned())
+            );
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
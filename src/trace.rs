@@ -0,0 +1,180 @@
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    sync::{Arc, Mutex},
+};
+
+// A .trace file is laid out as:
+//   magic (8 bytes) | version (u32 LE) | bin hash (u64 LE) | records
+// with every record being RECORD_SIZE bytes, all integers little endian:
+//   cpu (u16) | flags (u8) | eip (u64) | a_addr (u64) | a_before (i64) | b_val (i64)
+// The records of each CPU are in order, but those of different CPUs are only interleaved
+// by cycle, since the CPUs run concurrently within a cycle.
+pub const TRACE_MAGIC: [u8; 8] = *b"NTTRACE\0";
+pub const TRACE_VERSION: u32 = 1;
+pub const RECORD_SIZE: usize = 2 + 1 + 8 * 4;
+
+const FLAG_BRANCH_TAKEN: u8 = 1;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TraceRecord {
+    pub cpu: u16,
+    pub eip: u64,
+    pub a_addr: u64,
+    pub a_before: i64,
+    pub b_val: i64,
+    pub branch_taken: bool,
+}
+
+impl TraceRecord {
+    pub fn a_after(&self) -> i64 {
+        self.a_before.wrapping_sub(self.b_val)
+    }
+
+    pub fn to_bytes(&self) -> [u8; RECORD_SIZE] {
+        let mut ret = [0u8; RECORD_SIZE];
+        ret[0..2].copy_from_slice(&self.cpu.to_le_bytes());
+        ret[2] = if self.branch_taken {
+            FLAG_BRANCH_TAKEN
+        } else {
+            0
+        };
+        ret[3..11].copy_from_slice(&self.eip.to_le_bytes());
+        ret[11..19].copy_from_slice(&self.a_addr.to_le_bytes());
+        ret[19..27].copy_from_slice(&self.a_before.to_le_bytes());
+        ret[27..35].copy_from_slice(&self.b_val.to_le_bytes());
+        ret
+    }
+
+    pub fn from_bytes(data: &[u8; RECORD_SIZE]) -> Self {
+        TraceRecord {
+            cpu: u16::from_le_bytes(data[0..2].try_into().unwrap()),
+            branch_taken: data[2] & FLAG_BRANCH_TAKEN != 0,
+            eip: u64::from_le_bytes(data[3..11].try_into().unwrap()),
+            a_addr: u64::from_le_bytes(data[11..19].try_into().unwrap()),
+            a_before: i64::from_le_bytes(data[19..27].try_into().unwrap()),
+            b_val: i64::from_le_bytes(data[27..35].try_into().unwrap()),
+        }
+    }
+}
+
+// One per CPU, all writing into the same file. Records are buffered until flush() so that
+// the CPUs don't contend for the file on every instruction.
+pub struct TraceWriter {
+    out: Arc<Mutex<BufWriter<File>>>,
+    buf: Vec<u8>,
+}
+
+impl TraceWriter {
+    pub fn create(path: &str, bin_hash: u64) -> Self {
+        let mut out = BufWriter::new(File::create(path).unwrap());
+        out.write_all(&TRACE_MAGIC).unwrap();
+        out.write_all(&TRACE_VERSION.to_le_bytes()).unwrap();
+        out.write_all(&bin_hash.to_le_bytes()).unwrap();
+        TraceWriter {
+            out: Arc::new(Mutex::new(out)),
+            buf: Vec::new(),
+        }
+    }
+
+    // A writer for another CPU
+    pub fn share(&self) -> Self {
+        TraceWriter {
+            out: Arc::clone(&self.out),
+            buf: Vec::new(),
+        }
+    }
+
+    #[inline(always)]
+    pub fn record(&mut self, record: &TraceRecord) {
+        self.buf.extend_from_slice(&record.to_bytes());
+    }
+
+    pub fn flush(&mut self) {
+        self.out.lock().unwrap().write_all(&self.buf).unwrap();
+        self.buf.clear();
+    }
+}
+
+impl Drop for TraceWriter {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+pub struct TraceReader {
+    pub bin_hash: u64,
+    inp: BufReader<File>,
+}
+
+impl TraceReader {
+    pub fn open(path: &str) -> Result<Self, String> {
+        let file = File::open(path).map_err(|e| format!("Failed to open {path}: {e}"))?;
+        let mut inp = BufReader::new(file);
+
+        let mut header = [0u8; 8 + 4 + 8];
+        inp.read_exact(&mut header)
+            .map_err(|_| format!("{path} is too short to be a .trace file"))?;
+
+        if header[0..8] != TRACE_MAGIC {
+            return Err(format!("{path} is not a .trace file"));
+        }
+
+        let version = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        if version != TRACE_VERSION {
+            return Err(format!(
+                "Unsupported .trace version {version} (expected {TRACE_VERSION})"
+            ));
+        }
+
+        Ok(TraceReader {
+            bin_hash: u64::from_le_bytes(header[12..20].try_into().unwrap()),
+            inp,
+        })
+    }
+}
+
+impl Iterator for TraceReader {
+    type Item = TraceRecord;
+
+    fn next(&mut self) -> Option<TraceRecord> {
+        let mut data = [0u8; RECORD_SIZE];
+        // A truncated last record (e.g. from a crash) is silently dropped
+        self.inp.read_exact(&mut data).ok()?;
+        Some(TraceRecord::from_bytes(&data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shared_writers() {
+        let path = std::env::temp_dir().join(format!("shared-{}.trace", std::process::id()));
+        let path = path.to_str().unwrap();
+        let record = |cpu, eip| TraceRecord {
+            cpu,
+            eip,
+            a_addr: 0x100,
+            a_before: 5,
+            b_val: -1,
+            branch_taken: cpu == 1,
+        };
+
+        let mut cpu0 = TraceWriter::create(path, 0x1234);
+        let mut cpu1 = cpu0.share();
+        cpu0.record(&record(0, 0));
+        cpu1.record(&record(1, 24));
+        cpu1.flush();
+        cpu0.record(&record(0, 24));
+        drop(cpu0);
+        drop(cpu1);
+
+        let reader = TraceReader::open(path).unwrap();
+        assert_eq!(reader.bin_hash, 0x1234);
+        let records: Vec<TraceRecord> = reader.collect();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(records, [record(1, 24), record(0, 0), record(0, 24)]);
+    }
+}
@@ -0,0 +1,196 @@
+// Runs random, partly self-modifying programs built from common SUBLEQ patterns through the
+// block cache and checks it against the plain interpreter
+use std::panic::{self, AssertUnwindSafe};
+
+use noontide_emu::{block::BlockCache, exec, mem};
+
+const MEM_SIZE: usize = 0x2000;
+const CODE_SIZE: u64 = 0x900;
+const DATA_START: u64 = 0x1000;
+const DATA_END: u64 = 0x1100;
+
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        // xorshift64
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    // Mostly aligned words, sometimes straddling two of them
+    fn data_addr(&mut self) -> u64 {
+        if self.below(4) == 0 {
+            DATA_START + self.below(DATA_END - DATA_START - 7)
+        } else {
+            DATA_START + 8 * self.below((DATA_END - DATA_START) / 8)
+        }
+    }
+
+    // A data word partly or fully overlapping the one at addr
+    fn near(&mut self, addr: u64) -> u64 {
+        (addr + self.below(15)).clamp(DATA_START + 7, DATA_END - 1) - 7
+    }
+
+    // Mostly data, sometimes an instruction word to exercise self-modifying code
+    fn operand(&mut self) -> u64 {
+        if self.below(8) == 0 {
+            8 * self.below(CODE_SIZE / 8)
+        } else {
+            self.data_addr()
+        }
+    }
+
+    fn target(&mut self) -> u64 {
+        24 * self.below(CODE_SIZE / 24)
+    }
+}
+
+fn write_ins(mem: &mut [u8], eip: u64, a: u64, b: u64, c: u64) {
+    mem::write(mem, eip as usize, &a.to_be_bytes());
+    mem::write(mem, eip as usize + 8, &b.to_be_bytes());
+    mem::write(mem, eip as usize + 16, &c.to_be_bytes());
+}
+
+fn random_program(rng: &mut Rng) -> Vec<u8> {
+    let mut mem = vec![0u8; MEM_SIZE];
+    let mut eip = 0;
+    while eip + 96 <= CODE_SIZE {
+        let z = rng.data_addr();
+        let mut a = rng.data_addr();
+        let mut b = rng.operand();
+        if rng.below(4) == 0 {
+            a = rng.near(z);
+        }
+        if rng.below(4) == 0 {
+            b = rng.near(z);
+        }
+        match rng.below(4) {
+            0 => {
+                write_ins(&mut mem, eip, b, b, eip + 24);
+                write_ins(&mut mem, eip + 24, z, a, eip + 48);
+                write_ins(&mut mem, eip + 48, b, z, eip + 72);
+                write_ins(&mut mem, eip + 72, z, z, rng.target());
+                eip += 96;
+            }
+            1 => {
+                write_ins(&mut mem, eip, z, a, eip + 24);
+                write_ins(&mut mem, eip + 24, b, z, eip + 48);
+                write_ins(&mut mem, eip + 48, z, z, rng.target());
+                eip += 72;
+            }
+            2 => {
+                let a = rng.operand();
+                write_ins(&mut mem, eip, a, a, rng.target());
+                eip += 24;
+            }
+            _ => {
+                let c = if rng.below(2) == 0 {
+                    eip + 24
+                } else {
+                    rng.target()
+                };
+                write_ins(&mut mem, eip, rng.operand(), rng.operand(), c);
+                eip += 24;
+            }
+        }
+    }
+
+    for addr in (DATA_START..DATA_END).step_by(8) {
+        let val = rng.below(64) as i64 - 32;
+        mem::write(&mut mem, addr as usize, &val.to_be_bytes());
+    }
+
+    mem
+}
+
+// Self-modifying code can turn operands into out of bounds addresses, which panics
+fn catch_panic<T>(f: impl FnOnce() -> T) -> std::thread::Result<T> {
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    let ret = panic::catch_unwind(AssertUnwindSafe(f));
+    panic::set_hook(hook);
+    ret
+}
+
+// Executes instructions one by one like the emulator's slow path does, telling the block cache
+// about every write
+fn run_stepping(
+    cache: &mut BlockCache,
+    mem: &mut [u8],
+    eip: &mut u64,
+    budget: u64,
+) -> (u64, exec::Event) {
+    for executed in 0..budget {
+        if !exec::eip_in_bounds(mem, *eip) {
+            return (executed, exec::Event::EipOutOfBounds);
+        }
+        let ins = exec::step(mem, *eip);
+        *eip = ins.next_eip();
+        if ins.b_val != 0 {
+            cache.memory_written(mem, ins.a_addr);
+        }
+    }
+    (budget, exec::Event::Budget)
+}
+
+// Runs random programs through both exec::run() and the block cache, with random budgets,
+// and checks that they always end up in the same state. With stepping, some of the runs go
+// through run_stepping() instead of the block cache, the way the emulator switches between
+// them while debugging.
+fn compare_with_plain(seed: u64, idioms: bool, stepping: bool) {
+    let mut rng = Rng(seed);
+    for _program in 0..200 {
+        let mut plain_mem = random_program(&mut rng);
+        let mut fast_mem = plain_mem.clone();
+        let mut cache = BlockCache::new(&fast_mem, idioms);
+        let mut plain_eip = 0;
+        let mut fast_eip = 0;
+
+        for _run in 0..50 {
+            let budget = 1 + rng.below(500);
+            let plain = catch_panic(|| exec::run(&mut plain_mem, &mut plain_eip, budget));
+            let fast = if stepping && rng.below(2) == 0 {
+                catch_panic(|| run_stepping(&mut cache, &mut fast_mem, &mut fast_eip, budget))
+            } else {
+                catch_panic(|| cache.run(&mut fast_mem, &mut fast_eip, budget))
+            };
+
+            let (plain, fast) = match (plain, fast) {
+                (Ok(plain), Ok(fast)) => (plain, fast),
+                (Err(_), Err(_)) => break,
+                _ => panic!("Only one of the interpreters panicked"),
+            };
+
+            assert_eq!(plain, fast);
+            assert_eq!(plain_eip, fast_eip);
+            assert!(plain_mem == fast_mem);
+
+            if plain.1 == exec::Event::EipOutOfBounds {
+                break;
+            }
+        }
+    }
+}
+
+#[test]
+fn blocks_match_plain_interpreter() {
+    compare_with_plain(0xD1B54A32D192ED03, false, false);
+}
+
+#[test]
+fn idioms_match_plain_interpreter() {
+    compare_with_plain(0x9E3779B97F4A7C15, true, false);
+}
+
+#[test]
+fn stepping_between_blocks_matches_plain_interpreter() {
+    compare_with_plain(0xBF58476D1CE4E5B9, false, true);
+    compare_with_plain(0x94D049BB133111EB, true, true);
+}